@@ -0,0 +1,36 @@
+// rust-demo 同步策略示例: 在清单中设置 policy = "policy.example.js", 或 `sync --policy policy.example.js`.
+// 以下函数都是可选的, 必须是同步函数; 脚本只能通过 git.status / git.log 只读地查询清单中的仓库,
+// 不能访问文件系统和网络. 每次调用最长 5 秒.
+
+// 是否同步该仓库, repo 为 { url, path, branch, mirror }
+function shouldSync(repo) {
+  // 镜像总是同步; 其它仓库有未提交的修改时先不动它
+  if (repo.mirror) {
+    return true;
+  }
+  const status = git.status(repo.path);
+  return !status.dirty || status.dirty.staged + status.dirty.modified === 0;
+}
+
+// 拉取到新提交时调用, changelog 为 { from, to, commits: [{ id, author, summary }], stat }.
+// 返回 true 接受, false 或字符串 (拒绝原因) 拒绝, 被拒绝时分支保持不变
+function acceptCommits(repo, changelog) {
+  const wip = changelog.commits.filter((c) => /^(wip|fixup!)/i.test(c.summary));
+  if (wip.length > 0) {
+    return `包含未完成的提交 ${wip.map((c) => c.id.slice(0, 8)).join(", ")}`;
+  }
+  if (changelog.stat.deleted > 100) {
+    return `一次删除 ${changelog.stat.deleted} 个文件, 需要人工确认`;
+  }
+  console.log(repo.path, `接受 ${changelog.commits.length} 个新提交`);
+  return true;
+}
+
+// 合并会冲突时调用, conflicts 为 [{ path, ancestor, ours, theirs }].
+// 返回 "abort" | "ours" | "theirs" | "leave", 返回 undefined 时沿用清单中的 merge
+function onConflict(repo, conflicts) {
+  if (conflicts.every((c) => /(^|\/)(package-lock\.json|Cargo\.lock)$/.test(c.path))) {
+    return "theirs";
+  }
+  return undefined;
+}
@@ -0,0 +1,324 @@
+use crate::cancel;
+use crate::cli::{BenchFormat, Strategy};
+use crate::credentials::Credentials;
+use crate::error::SyncError;
+use crate::progress::Silent;
+use crate::repo::{Repo, RepoOptions, SyncContext};
+use crate::retry::RetryPolicy;
+use crate::status;
+use git2::Repository;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub struct Options {
+    pub url: String,
+    pub branch: String,
+    pub runs: usize,
+    pub warmup: usize,
+    /// 为空时比较全部方式
+    pub strategies: Vec<Strategy>,
+    pub dir: Option<PathBuf>,
+}
+
+/// 一次克隆的测量结果, `bytes` 和 `objects` 是克隆后对象库的磁盘占用和对象数
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub millis: f64,
+    pub bytes: u64,
+    pub objects: usize,
+}
+
+/// 一种方式多次克隆的统计, 时间单位为毫秒
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub strategy: &'static str,
+    pub runs: usize,
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub bytes: u64,
+    pub objects: usize,
+    pub samples: Vec<Sample>,
+}
+
+impl Summary {
+    fn new(strategy: Strategy, samples: Vec<Sample>) -> Summary {
+        let mut millis: Vec<f64> = samples.iter().map(|s| s.millis).collect();
+        millis.sort_by(f64::total_cmp);
+        let first = samples.first();
+        Summary {
+            strategy: strategy.name(),
+            runs: samples.len(),
+            min: millis.first().copied().unwrap_or_default(),
+            median: median(&millis),
+            p95: percentile(&millis, 0.95),
+            bytes: first.map_or(0, |s| s.bytes),
+            objects: first.map_or(0, |s| s.objects),
+            samples,
+        }
+    }
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => sorted[n / 2],
+        n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+    }
+}
+
+/// 最近秩法, 样本很少时 P95 就是最大值
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// 轮流用每种方式克隆, 交错执行让网络和磁盘缓存的变化平均分摊到各方式上.
+/// 任何一次克隆失败都会中止测试
+pub fn run(opts: &Options) -> Result<Vec<Summary>, SyncError> {
+    let strategies = if opts.strategies.is_empty() {
+        vec![
+            Strategy::Builder,
+            Strategy::Download,
+            Strategy::Libgit2,
+            Strategy::Cmd,
+        ]
+    } else {
+        opts.strategies.clone()
+    };
+    let (dir, temporary) = match &opts.dir {
+        Some(dir) => (dir.clone(), false),
+        None => {
+            let name = format!("rust-demo-bench-{}", process::id());
+            (std::env::temp_dir().join(name), true)
+        }
+    };
+    fs::create_dir_all(&dir)?;
+    let result = run_in(opts, &strategies, &dir);
+    if temporary {
+        let _ = fs::remove_dir_all(&dir);
+    }
+    let samples = result?;
+    Ok(strategies
+        .into_iter()
+        .zip(samples)
+        .map(|(s, samples)| Summary::new(s, samples))
+        .collect())
+}
+
+fn run_in(
+    opts: &Options,
+    strategies: &[Strategy],
+    dir: &Path,
+) -> Result<Vec<Vec<Sample>>, SyncError> {
+    let ctx = SyncContext {
+        creds: Credentials::default(),
+        progress: Box::new(Silent),
+        cache: None,
+        policy: None,
+    };
+    let mut samples = vec![vec![]; strategies.len()];
+    for i in 0..opts.warmup + opts.runs {
+        for (&strategy, samples) in strategies.iter().zip(&mut samples) {
+            if cancel::requested() {
+                return Err(SyncError::Cancelled);
+            }
+            let path = dir.join(format!("{}-{}", strategy.name(), i));
+            if path.exists() {
+                fs::remove_dir_all(&path)?;
+            }
+            let start = Instant::now();
+            clone(strategy, opts, &path, &ctx)?;
+            let millis = start.elapsed().as_secs_f64() * 1000.0;
+            let (bytes, objects) = measure(&path)?;
+            fs::remove_dir_all(&path)?;
+            eprintln!(
+                "{} #{}{}: {:.1} ms",
+                strategy.name(),
+                i + 1,
+                if i < opts.warmup { " (预热)" } else { "" },
+                millis
+            );
+            if i >= opts.warmup {
+                samples.push(Sample {
+                    millis,
+                    bytes,
+                    objects,
+                });
+            }
+        }
+    }
+    Ok(samples)
+}
+
+fn clone(
+    strategy: Strategy,
+    opts: &Options,
+    path: &Path,
+    ctx: &SyncContext,
+) -> Result<(), SyncError> {
+    let path = path.to_string_lossy().into_owned();
+    match strategy.clone_strategy() {
+        Some(clone) => {
+            let repo = Repo {
+                url: opts.url.clone(),
+                path,
+                branch: opts.branch.clone(),
+                options: RepoOptions {
+                    clone,
+                    // 测试要反映一次克隆的真实耗时, 失败时不重试
+                    retry: RetryPolicy {
+                        attempts: 1,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            };
+            repo.clone(ctx)
+        }
+        None => {
+            Repository::clone(&opts.url, &path)?;
+            Ok(())
+        }
+    }
+}
+
+/// 对象库的磁盘占用和对象数
+fn measure(path: &Path) -> Result<(u64, usize), SyncError> {
+    let repo = Repository::open(path)?;
+    let mut objects = 0;
+    repo.odb()?.foreach(|_| {
+        objects += 1;
+        true
+    })?;
+    Ok((dir_size(&repo.path().join("objects"))?, objects))
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// CSV 字段中有逗号或引号时加引号转义
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn print(
+    summaries: &[Summary],
+    opts: &Options,
+    format: BenchFormat,
+) -> Result<(), serde_json::Error> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    match format {
+        BenchFormat::Table => {
+            let header = ["方式", "次数", "最小", "中位数", "P95", "对象库", "对象数"];
+            let rows: Vec<[String; 7]> = summaries
+                .iter()
+                .map(|s| {
+                    [
+                        s.strategy.to_string(),
+                        s.runs.to_string(),
+                        format!("{:.1} ms", s.min),
+                        format!("{:.1} ms", s.median),
+                        format!("{:.1} ms", s.p95),
+                        human_bytes(s.bytes),
+                        s.objects.to_string(),
+                    ]
+                })
+                .collect();
+            status::print_aligned(&header, &rows);
+        }
+        BenchFormat::Csv => {
+            println!("time,url,branch,strategy,runs,min_ms,median_ms,p95_ms,bytes,objects");
+            for s in summaries {
+                println!(
+                    "{},{},{},{},{},{:.3},{:.3},{:.3},{},{}",
+                    time,
+                    csv_field(&opts.url),
+                    csv_field(&opts.branch),
+                    s.strategy,
+                    s.runs,
+                    s.min,
+                    s.median,
+                    s.p95,
+                    s.bytes,
+                    s.objects
+                );
+            }
+        }
+        BenchFormat::Json => {
+            let report = serde_json::json!({
+                "time": time,
+                "url": opts.url,
+                "branch": opts.branch,
+                "warmup": opts.warmup,
+                "results": summaries,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_samples() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(percentile(&[], 0.95), 0.0);
+    }
+
+    #[test]
+    fn single_sample() {
+        assert_eq!(median(&[3.0]), 3.0);
+        assert_eq!(percentile(&[3.0], 0.5), 3.0);
+        assert_eq!(percentile(&[3.0], 0.95), 3.0);
+    }
+
+    #[test]
+    fn even_number_of_samples() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(median(&sorted), 2.5);
+        // 最近秩法取实际的样本, 不在两个样本间插值
+        assert_eq!(percentile(&sorted, 0.5), 2.0);
+        assert_eq!(percentile(&sorted, 0.95), 4.0);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(median(&[1.0, 2.0, 4.0]), 2.0);
+    }
+}
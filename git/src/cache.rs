@@ -0,0 +1,104 @@
+use crate::error::SyncError;
+use git2::{Remote, Repository};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// 按远端地址共享的对象缓存: `dir` 下每个地址一个裸仓库, 开启了 `shared` 的工作仓库
+/// 通过 `objects/info/alternates` 引用其中的对象, 同一地址的 N 个检出只占一份对象.
+///
+/// 缓存只会拉取, 从不删除对象; 不要对缓存执行 `git gc --prune` 或删除缓存目录,
+/// 否则引用它的仓库会丢失对象
+pub struct ObjectCache {
+    dir: PathBuf,
+    /// 每个缓存仓库一把锁, 同一地址的多个仓库并发同步时依次更新缓存
+    locks: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl ObjectCache {
+    pub fn new(dir: PathBuf) -> ObjectCache {
+        ObjectCache {
+            dir,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `url` 对应的缓存仓库: 取地址最后一段作为可读的名字, 加上地址的哈希避免重名
+    pub fn path_for(&self, url: &str) -> PathBuf {
+        let last = url
+            .trim_end_matches('/')
+            .rsplit(['/', ':', '\\'])
+            .next()
+            .unwrap_or_default();
+        let name: String = last
+            .trim_end_matches(".git")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir
+            .join(format!("{}-{:016x}.git", name, xxh3_64(url.as_bytes())))
+    }
+
+    /// 创建或更新 `url` 的缓存仓库, 由 `fetch` 拉取其 origin 远端. 返回缓存对象目录的
+    /// 绝对路径, 用于写入 alternates
+    pub fn update(
+        &self,
+        url: &str,
+        fetch: impl FnOnce(&Repository, &mut Remote) -> Result<(), SyncError>,
+    ) -> Result<PathBuf, SyncError> {
+        let path = self.path_for(url);
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(path.clone())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let repo = if path.exists() {
+            Repository::open_bare(&path).map_err(|e| SyncError::open(&path, e))?
+        } else {
+            let repo = Repository::init_bare(&path)?;
+            repo.remote_with_fetch("origin", url, "+refs/heads/*:refs/heads/*")?;
+            repo.remote_add_fetch("origin", "+refs/tags/*:refs/tags/*")?;
+            repo
+        };
+        fetch(&repo, &mut repo.find_remote("origin")?)?;
+        Ok(fs::canonicalize(repo.path())?.join("objects"))
+    }
+}
+
+/// 把 `objects` 加入 `repo` 的 alternates, 已经存在时不做修改. 返回是否新加入
+pub fn link(repo: &Repository, objects: &Path) -> io::Result<bool> {
+    let file = repo.path().join("objects/info/alternates");
+    let existing = match fs::read_to_string(&file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let line = objects.to_string_lossy();
+    if existing.lines().any(|l| l.trim() == line) {
+        return Ok(false);
+    }
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut text = existing;
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(&line);
+    text.push('\n');
+    fs::write(&file, text)?;
+    Ok(true)
+}
@@ -0,0 +1,139 @@
+use crate::error::SyncError;
+use crate::progress::Transfer;
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// 安装 Ctrl-C 处理. 第一次只设置取消标记: 进行中的传输在下一次回调时中止,
+/// 已经开始的合并和检出照常完成, 尚未开始的仓库直接跳过; 第二次立即退出
+pub fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+        eprintln!("\n正在取消, 等待进行中的传输中止, 再按一次 Ctrl-C 强制退出");
+    });
+    if let Err(e) = result {
+        eprintln!("安装 Ctrl-C 处理失败: {}", e);
+    }
+}
+
+/// 是否已经按过 Ctrl-C
+pub fn requested() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// 可以被取消打断的 sleep, 返回是否睡满了 `duration`
+pub fn sleep(duration: Duration) -> bool {
+    const STEP: Duration = Duration::from_millis(200);
+    let deadline = Instant::now() + duration;
+    loop {
+        if requested() {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(STEP));
+    }
+}
+
+/// git2 还没有封装的 `GIT_OPT_SET_SERVER_TIMEOUT` (libgit2 1.7)
+const GIT_OPT_SET_SERVER_TIMEOUT: c_int = 41;
+
+/// 设置 HTTP(S) 套接字的读写超时. 服务端完全不再发送数据时 libgit2 不会调用任何回调,
+/// [`Watchdog`] 无从判断, 只能靠套接字超时让读取失败. 这是进程级的设置, SSH 不受影响
+pub fn set_socket_timeout(timeout: Duration) {
+    let ms = c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX);
+    libgit2_sys::init();
+    // SAFETY: 该选项只接受一个 int 参数
+    if unsafe { libgit2_sys::git_libgit2_opts(GIT_OPT_SET_SERVER_TIMEOUT, ms) } < 0 {
+        eprintln!(
+            "设置网络超时失败: {}",
+            git2::Error::last_error(-1).map_or_else(String::new, |e| e.message().to_string())
+        );
+    }
+}
+
+/// 传输被中止的原因
+#[derive(Debug, Clone, Copy)]
+enum Abort {
+    Cancelled,
+    Timeout(Duration),
+    Stalled(Duration),
+}
+
+/// 一次 clone/fetch 的看门狗, 在 libgit2 的传输回调中检查取消标记, 总时长和
+/// 进展, 需要中止时让回调返回 false. 每次尝试 (包括重试) 都要新建一个
+pub struct Watchdog {
+    timeout: Option<Duration>,
+    stall: Option<Duration>,
+    started: Instant,
+    /// 上一次回调时的 (已接收字节, 已索引对象, 已索引增量), 任何一项增加都算有进展
+    last: Cell<(usize, usize, usize)>,
+    progressed: Cell<Instant>,
+    abort: Cell<Option<Abort>>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Option<Duration>, stall: Option<Duration>) -> Watchdog {
+        let now = Instant::now();
+        Watchdog {
+            timeout,
+            stall,
+            started: now,
+            last: Cell::new((0, 0, 0)),
+            progressed: Cell::new(now),
+            abort: Cell::new(None),
+        }
+    }
+
+    /// 在传输回调中调用, 旁带消息 (远端的进度文字) 没有传输统计, 传 `None`.
+    /// 返回 false 时 libgit2 中止传输, 已拉取的引用不会被更新
+    pub fn tick(&self, stats: Option<&Transfer>) -> bool {
+        let now = Instant::now();
+        if let Some(s) = stats {
+            let current = (s.received_bytes, s.indexed_objects, s.indexed_deltas);
+            if current != self.last.get() {
+                self.last.set(current);
+                self.progressed.set(now);
+            }
+        }
+        let abort = if requested() {
+            Some(Abort::Cancelled)
+        } else if let Some(limit) = self.timeout.filter(|&t| now - self.started > t) {
+            Some(Abort::Timeout(limit))
+        } else {
+            self.stall
+                .filter(|&t| now - self.progressed.get() > t)
+                .map(Abort::Stalled)
+        };
+        if abort.is_some() {
+            self.abort.set(abort);
+        }
+        abort.is_none()
+    }
+
+    /// 把被看门狗中止的传输报告为 [`SyncError::Cancelled`] 或 [`SyncError::Timeout`]
+    /// 而不是 libgit2 笼统的回调错误
+    pub fn check<T>(&self, result: Result<T, git2::Error>) -> Result<T, SyncError> {
+        result.map_err(|e| match self.abort.get() {
+            None => e.into(),
+            Some(Abort::Cancelled) => SyncError::Cancelled,
+            Some(Abort::Timeout(limit)) => SyncError::Timeout {
+                limit,
+                stalled: false,
+            },
+            Some(Abort::Stalled(limit)) => SyncError::Timeout {
+                limit,
+                stalled: true,
+            },
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use git2::{Delta, DiffFindOptions, Oid, Repository, Sort};
+use serde::Serialize;
+
+/// 一次同步带来的新提交
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedCommit {
+    pub id: String,
+    pub author: String,
+    pub summary: String,
+}
+
+/// 一个文件的变化, `status` 为 added/modified/deleted/renamed/typechange
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub status: &'static str,
+    /// 重命名前的路径
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+}
+
+/// 文件级别的差异统计
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiffStat {
+    pub added: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub files: Vec<FileChange>,
+}
+
+/// 同步前后两个 HEAD 之间的变化
+#[derive(Debug, Clone, Serialize)]
+pub struct Changelog {
+    pub from: String,
+    pub to: String,
+    /// 从新到旧, 只包含 `to` 可达而 `from` 不可达的提交
+    pub commits: Vec<ChangedCommit>,
+    pub stat: DiffStat,
+}
+
+impl Changelog {
+    pub fn between(repo: &Repository, from: Oid, to: Oid) -> Result<Changelog, git2::Error> {
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        walk.push(to)?;
+        walk.hide(from)?;
+        let mut commits = vec![];
+        for id in walk {
+            let commit = repo.find_commit(id?)?;
+            commits.push(ChangedCommit {
+                id: commit.id().to_string(),
+                author: commit.author().name().unwrap_or_default().to_string(),
+                summary: commit.summary().unwrap_or_default().to_string(),
+            });
+        }
+
+        let old = repo.find_commit(from)?.tree()?;
+        let new = repo.find_commit(to)?.tree()?;
+        let mut diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+        let mut stat = DiffStat::default();
+        for delta in diff.deltas() {
+            let status = match delta.status() {
+                Delta::Added => {
+                    stat.added += 1;
+                    "added"
+                }
+                Delta::Deleted => {
+                    stat.deleted += 1;
+                    "deleted"
+                }
+                Delta::Renamed => {
+                    stat.renamed += 1;
+                    "renamed"
+                }
+                Delta::Typechange => {
+                    stat.modified += 1;
+                    "typechange"
+                }
+                _ => {
+                    stat.modified += 1;
+                    "modified"
+                }
+            };
+            let path = |f: git2::DiffFile| f.path().map(|p| p.display().to_string());
+            stat.files.push(FileChange {
+                path: path(delta.new_file())
+                    .or(path(delta.old_file()))
+                    .unwrap_or_default(),
+                status,
+                old_path: (delta.status() == Delta::Renamed)
+                    .then(|| path(delta.old_file()))
+                    .flatten(),
+            });
+        }
+        let totals = diff.stats()?;
+        stat.insertions = totals.insertions();
+        stat.deletions = totals.deletions();
+
+        Ok(Changelog {
+            from: from.to_string(),
+            to: to.to_string(),
+            commits,
+            stat,
+        })
+    }
+}
@@ -0,0 +1,210 @@
+use crate::merge::MergeStrategy;
+use crate::progress::{JsonLines, Progress, Silent, Terminal};
+use crate::repo::{CloneStrategy, DirtyPolicy};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// 多仓库同步工具
+#[derive(Debug, Parser)]
+#[command(name = "rust-demo", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /// 进度输出方式
+    #[arg(long, global = true, value_enum, default_value_t = ProgressStyle::Bar)]
+    pub progress: ProgressStyle,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ProgressStyle {
+    /// 终端进度条
+    Bar,
+    /// 每个事件一行 JSON
+    Json,
+    /// 不输出进度
+    None,
+}
+
+impl ProgressStyle {
+    pub fn progress(self) -> Box<dyn Progress> {
+        match self {
+            ProgressStyle::Bar => Box::<Terminal>::default(),
+            ProgressStyle::Json => Box::new(JsonLines),
+            ProgressStyle::None => Box::new(Silent),
+        }
+    }
+}
+
+/// 报告的输出格式
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// 按清单同步所有仓库
+    Sync {
+        /// 清单文件 (TOML 或 JSON)
+        #[arg(default_value = "repos.toml")]
+        manifest: PathBuf,
+        /// 同时同步的仓库数, 默认取清单中的 concurrency 或 CPU 核心数
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// 把变更日志追加到该文件, 覆盖清单中的 changelog
+        #[arg(long)]
+        changelog: Option<PathBuf>,
+        /// JavaScript 策略脚本, 覆盖清单中的 policy
+        #[arg(long)]
+        policy: Option<PathBuf>,
+    },
+    /// 常驻运行, 按各仓库的间隔反复同步, 失败后指数退避重试
+    Daemon {
+        #[arg(default_value = "repos.toml")]
+        manifest: PathBuf,
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// 默认同步间隔 (秒), 覆盖清单中的 interval
+        #[arg(long)]
+        interval: Option<u64>,
+        /// 每轮同步后把各仓库的最近结果写入该 JSON 文件
+        #[arg(long)]
+        state: Option<PathBuf>,
+        /// 在该地址以 HTTP 提供各仓库的最近结果
+        #[arg(long)]
+        listen: Option<String>,
+        #[arg(long)]
+        changelog: Option<PathBuf>,
+        #[arg(long)]
+        policy: Option<PathBuf>,
+    },
+    /// 克隆单个仓库
+    Clone {
+        url: String,
+        path: String,
+        #[arg(short, long, default_value = "master")]
+        branch: String,
+        #[arg(short, long, value_enum, default_value_t = Strategy::Builder)]
+        strategy: Strategy,
+        /// 浅克隆深度 (libgit2 除外)
+        #[arg(long)]
+        depth: Option<u32>,
+        /// 只克隆 branch 一个分支 (libgit2 除外)
+        #[arg(long)]
+        single_branch: bool,
+        /// 不下载标签 (libgit2 除外)
+        #[arg(long)]
+        no_tags: bool,
+        /// 远端的 fetch refspec, 可以重复 (仅 builder 和 download)
+        #[arg(long = "refspec")]
+        refspecs: Vec<String>,
+    },
+    /// 拉取已存在的仓库
+    Pull {
+        path: String,
+        #[arg(short, long, default_value = "master")]
+        branch: String,
+        #[arg(short, long, default_value = "origin")]
+        remote: String,
+        /// 工作区有本地修改时的处理方式
+        #[arg(long, value_enum, default_value_t)]
+        dirty: DirtyPolicy,
+        /// 合并冲突时的处理方式
+        #[arg(long, value_enum, default_value_t = MergeStrategy::Abort)]
+        merge: MergeStrategy,
+        /// 把本地提交变基到远端分支之上, 而不是生成合并提交
+        #[arg(long)]
+        rebase: bool,
+    },
+    /// 查看清单中各仓库的分支, HEAD, 领先/落后, 本地修改和上次同步时间
+    Status {
+        #[arg(default_value = "repos.toml")]
+        manifest: PathBuf,
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// 启动静态页面服务
+    Serve {
+        #[arg(short, long, default_value = "127.0.0.1:3000")]
+        addr: String,
+    },
+    /// 性能测试
+    Bench {
+        #[command(subcommand)]
+        target: BenchTarget,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BenchTarget {
+    /// xxh3 哈希
+    Hash {
+        #[arg(short = 'n', long, default_value_t = 10000)]
+        iterations: u32,
+    },
+    /// 用各种克隆方式轮流克隆同一个仓库, 统计耗时的最小值, 中位数和 P95
+    Clone {
+        /// 仓库地址; 本地裸仓库要写成 file:// 地址, 否则会直接复制对象而不经过传输
+        url: String,
+        #[arg(short, long, default_value = "master")]
+        branch: String,
+        /// 每种方式计入统计的克隆次数
+        #[arg(short = 'n', long, default_value_t = 5)]
+        runs: usize,
+        /// 每种方式先克隆几次预热, 不计入统计
+        #[arg(long, default_value_t = 1)]
+        warmup: usize,
+        /// 参与比较的方式, 可以重复, 默认全部
+        #[arg(short, long = "strategy", value_enum)]
+        strategies: Vec<Strategy>,
+        /// 存放克隆结果的目录, 每次克隆后删除; 默认使用系统临时目录
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = BenchFormat::Table)]
+        format: BenchFormat,
+    },
+}
+
+/// 克隆性能测试结果的输出格式
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum BenchFormat {
+    Table,
+    /// 每种方式一行, 带测试时间和仓库地址, 便于汇总多次测试的结果
+    Csv,
+    Json,
+}
+
+/// 克隆方式
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Strategy {
+    /// RepoBuilder, 使用默认凭据链
+    Builder,
+    /// git2::Repository::clone
+    Libgit2,
+    /// 调用 git 命令行
+    Cmd,
+    /// 先 init 再 fetch
+    Download,
+}
+
+impl Strategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::Builder => "builder",
+            Strategy::Libgit2 => "libgit2",
+            Strategy::Cmd => "cmd",
+            Strategy::Download => "download",
+        }
+    }
+
+    /// 清单中也能选择的克隆方式, libgit2 只用于对比耗时
+    pub fn clone_strategy(self) -> Option<CloneStrategy> {
+        match self {
+            Strategy::Builder => Some(CloneStrategy::Builder),
+            Strategy::Download => Some(CloneStrategy::Download),
+            Strategy::Cmd => Some(CloneStrategy::Cmd),
+            Strategy::Libgit2 => None,
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use git2::{
+    Config, Cred, CredentialType, Error, ErrorClass, ErrorCode, RemoteCallbacks, Repository,
+};
+use serde::Deserialize;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// 提供一种认证方式, 不适用于当前请求时返回 `Err`, 调用方会继续尝试下一个
+pub trait CredentialProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// SSH 认证前 libgit2 单独询问用户名时使用的配置, 没有时用地址中的用户名
+    fn username(&self) -> Option<&str> {
+        None
+    }
+
+    /// `config` 是发起请求的仓库的 git 配置 (包含全局配置), 新克隆时只有全局配置,
+    /// 读取失败时为 `None`
+    fn credential(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+        config: Option<&Config>,
+    ) -> Result<Cred, Error>;
+}
+
+/// 从环境变量读取用户名和密码 (或 token)
+pub struct EnvCredentials {
+    pub username_env: String,
+    pub password_env: String,
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn credential(
+        &self,
+        _url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+        _config: Option<&Config>,
+    ) -> Result<Cred, Error> {
+        if !allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            return Err(Error::from_str("env: 不支持的认证类型"));
+        }
+        let password = env::var(&self.password_env)
+            .map_err(|_| Error::from_str(&format!("env: 未设置 {}", self.password_env)))?;
+        let username = match env::var(&self.username_env) {
+            Ok(u) => u,
+            Err(_) => username_from_url
+                .map(str::to_string)
+                .ok_or_else(|| Error::from_str(&format!("env: 未设置 {}", self.username_env)))?,
+        };
+        Cred::userpass_plaintext(&username, &password)
+    }
+}
+
+/// 使用 git 配置中的 credential.helper, 仓库自己配置的优先
+pub struct HelperCredentials;
+
+impl CredentialProvider for HelperCredentials {
+    fn name(&self) -> &str {
+        "helper"
+    }
+
+    fn credential(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+        config: Option<&Config>,
+    ) -> Result<Cred, Error> {
+        if !allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            return Err(Error::from_str("helper: 不支持的认证类型"));
+        }
+        let config = config.ok_or_else(|| Error::from_str("helper: 无法读取 git 配置"))?;
+        Cred::credential_helper(config, url, username_from_url)
+    }
+}
+
+/// 通过 ssh-agent 认证
+pub struct SshAgentCredentials {
+    pub username: Option<String>,
+}
+
+impl CredentialProvider for SshAgentCredentials {
+    fn name(&self) -> &str {
+        "ssh-agent"
+    }
+
+    fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    fn credential(
+        &self,
+        _url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+        _config: Option<&Config>,
+    ) -> Result<Cred, Error> {
+        let username = ssh_username(self.username.as_deref(), username_from_url);
+        if !allowed.contains(CredentialType::SSH_KEY) {
+            return Err(Error::from_str("ssh-agent: 不支持的认证类型"));
+        }
+        Cred::ssh_key_from_agent(username)
+    }
+}
+
+/// 使用磁盘上的 SSH 私钥, 口令从环境变量读取
+pub struct SshKeyCredentials {
+    pub username: Option<String>,
+    pub private_key: PathBuf,
+    pub public_key: Option<PathBuf>,
+    pub passphrase_env: Option<String>,
+}
+
+impl CredentialProvider for SshKeyCredentials {
+    fn name(&self) -> &str {
+        "ssh-key"
+    }
+
+    fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    fn credential(
+        &self,
+        _url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+        _config: Option<&Config>,
+    ) -> Result<Cred, Error> {
+        let username = ssh_username(self.username.as_deref(), username_from_url);
+        if !allowed.contains(CredentialType::SSH_KEY) {
+            return Err(Error::from_str("ssh-key: 不支持的认证类型"));
+        }
+        let passphrase = match &self.passphrase_env {
+            Some(var) => Some(
+                env::var(var).map_err(|_| Error::from_str(&format!("ssh-key: 未设置 {}", var)))?,
+            ),
+            None => None,
+        };
+        Cred::ssh_key(
+            username,
+            self.public_key.as_deref(),
+            &expand_home(&self.private_key),
+            passphrase.as_deref(),
+        )
+    }
+}
+
+fn ssh_username<'a>(configured: Option<&'a str>, from_url: Option<&'a str>) -> &'a str {
+    configured.or(from_url).unwrap_or("git")
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// 清单中的一条凭据配置
+///
+/// ```toml
+/// [[credentials]]
+/// host = "gitee.com"
+/// kind = "env"
+/// username_env = "GITEE_USER"
+/// password_env = "GITEE_TOKEN"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct HostCredential {
+    /// 匹配远端地址中的主机名, 省略时对所有主机生效
+    pub host: Option<String>,
+    #[serde(flatten)]
+    pub source: CredentialSource,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum CredentialSource {
+    Env {
+        username_env: String,
+        password_env: String,
+    },
+    Helper,
+    SshAgent {
+        username: Option<String>,
+    },
+    SshKey {
+        username: Option<String>,
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase_env: Option<String>,
+    },
+}
+
+impl CredentialSource {
+    fn provider(&self) -> Box<dyn CredentialProvider> {
+        match self.clone() {
+            CredentialSource::Env {
+                username_env,
+                password_env,
+            } => Box::new(EnvCredentials {
+                username_env,
+                password_env,
+            }),
+            CredentialSource::Helper => Box::new(HelperCredentials),
+            CredentialSource::SshAgent { username } => Box::new(SshAgentCredentials { username }),
+            CredentialSource::SshKey {
+                username,
+                private_key,
+                public_key,
+                passphrase_env,
+            } => Box::new(SshKeyCredentials {
+                username,
+                private_key,
+                public_key,
+                passphrase_env,
+            }),
+        }
+    }
+}
+
+/// 按主机组织的认证提供者链, clone 和 fetch 共用
+///
+/// 先尝试清单中匹配该主机的配置, 再依次尝试 ssh-agent, credential helper
+/// 以及 `GIT_USERNAME`/`GIT_PASSWORD` 环境变量.
+pub struct Credentials {
+    hosts: Vec<(Option<String>, Box<dyn CredentialProvider>)>,
+    fallback: Vec<Box<dyn CredentialProvider>>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::new(&[])
+    }
+}
+
+impl Credentials {
+    pub fn new(config: &[HostCredential]) -> Credentials {
+        Credentials {
+            hosts: config
+                .iter()
+                .map(|c| (c.host.clone(), c.source.provider()))
+                .collect(),
+            fallback: vec![
+                Box::new(SshAgentCredentials { username: None }),
+                Box::new(HelperCredentials),
+                Box::new(EnvCredentials {
+                    username_env: "GIT_USERNAME".to_string(),
+                    password_env: "GIT_PASSWORD".to_string(),
+                }),
+            ],
+        }
+    }
+
+    fn providers_for(&self, url: &str) -> Vec<&dyn CredentialProvider> {
+        let host = url_host(url);
+        self.hosts
+            .iter()
+            .filter(|(h, _)| match h {
+                Some(h) => host.is_some_and(|host| host.eq_ignore_ascii_case(h)),
+                None => true,
+            })
+            .map(|(_, p)| p.as_ref())
+            .chain(self.fallback.iter().map(|p| p.as_ref()))
+            .collect()
+    }
+
+    /// 注册认证回调. libgit2 在认证被拒后会再次调用回调, 所以每次调用都换下一个提供者,
+    /// 全部用完后返回错误, 避免无限重试. `repo` 是拉取的仓库, 读取其中的配置
+    /// (例如仓库级的 credential.helper); 新克隆还没有仓库时为 `None`, 只读全局配置
+    pub fn install<'a>(
+        &'a self,
+        url: &str,
+        repo: Option<&Repository>,
+        cb: &mut RemoteCallbacks<'a>,
+    ) {
+        cb.credentials(self.callback(url, repo));
+    }
+
+    fn callback<'a>(
+        &'a self,
+        url: &str,
+        repo: Option<&Repository>,
+    ) -> impl FnMut(&str, Option<&str>, CredentialType) -> Result<Cred, Error> + 'a {
+        let providers = self.providers_for(url);
+        let config = repo
+            .map_or_else(Config::open_default, Repository::config)
+            .ok();
+        let mut next = 0;
+        move |url, username_from_url, allowed| {
+            // SSH 认证前先只问用户名, 这不算一次尝试, 否则下一个提供者的位置会被用掉
+            if allowed == CredentialType::USERNAME {
+                let configured = providers[next..].iter().find_map(|p| p.username());
+                return Cred::username(ssh_username(configured, username_from_url));
+            }
+            let mut tried = vec![];
+            while next < providers.len() {
+                let provider = providers[next];
+                next += 1;
+                match provider.credential(url, username_from_url, allowed, config.as_ref()) {
+                    Ok(cred) => return Ok(cred),
+                    Err(e) => tried.push(format!("{}: {}", provider.name(), e.message())),
+                }
+            }
+            Err(Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Callback,
+                format!("{} 认证失败, 已尝试全部凭据 [{}]", url, tried.join("; ")),
+            ))
+        }
+    }
+}
+
+/// 解析远端地址中的主机名, 支持 `https://user@host:port/path`, `ssh://host/path`
+/// 以及 scp 风格的 `git@host:path`
+pub fn url_host(url: &str) -> Option<&str> {
+    let rest = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None if url.contains(':') && !Path::new(url).exists() => url,
+        None => return None,
+    };
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_request_does_not_use_up_provider() {
+        let creds = Credentials {
+            hosts: vec![(
+                Some("example.com".to_string()),
+                CredentialSource::SshKey {
+                    username: Some("deploy".to_string()),
+                    private_key: PathBuf::from("/nonexistent/id_ed25519"),
+                    public_key: None,
+                    passphrase_env: None,
+                }
+                .provider(),
+            )],
+            fallback: vec![],
+        };
+        let url = "ssh://example.com/repo.git";
+        let mut callback = creds.callback(url, None);
+        let cred = callback(url, None, CredentialType::USERNAME).unwrap();
+        assert_eq!(cred.credtype(), libgit2_sys::GIT_CREDTYPE_USERNAME);
+        let cred = callback(url, Some("deploy"), CredentialType::SSH_KEY).unwrap();
+        assert_eq!(cred.credtype(), libgit2_sys::GIT_CREDTYPE_SSH_KEY);
+        // 唯一的提供者已用过, 再次请求时报错而不是无限重试
+        assert!(callback(url, Some("deploy"), CredentialType::SSH_KEY).is_err());
+    }
+
+    #[test]
+    fn helper_reads_repository_config() {
+        let dir = std::env::temp_dir().join(format!("rust-demo-helper-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(
+                "credential.helper",
+                "!f() { echo username=repo-user; echo password=repo-pass; }; f",
+            )
+            .unwrap();
+        let creds = Credentials {
+            hosts: vec![(None, CredentialSource::Helper.provider())],
+            fallback: vec![],
+        };
+        let url = "https://example.com/repo.git";
+        let mut callback = creds.callback(url, Some(&repo));
+        let cred = callback(url, None, CredentialType::USER_PASS_PLAINTEXT).unwrap();
+        assert_eq!(
+            cred.credtype(),
+            libgit2_sys::GIT_CREDTYPE_USERPASS_PLAINTEXT
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
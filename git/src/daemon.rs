@@ -0,0 +1,409 @@
+use crate::cancel;
+use crate::error::SyncError;
+use crate::pool::{self, Finished};
+use crate::repo::{Repo, SyncContext, SyncReport};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::iter;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 失败后第一次重试的等待时间 (秒), 之后每次翻倍
+const BACKOFF_BASE: u64 = 30;
+/// 重试等待时间的上限 (秒)
+const BACKOFF_MAX: u64 = 3600;
+
+pub struct Options {
+    pub concurrency: usize,
+    /// 清单中没有单独设置时的同步间隔 (秒)
+    pub interval: u64,
+    /// 每轮同步后把 [`RepoState`] 列表写入该 JSON 文件
+    pub state: Option<PathBuf>,
+    /// 以 HTTP 提供 [`RepoState`] 列表的地址
+    pub listen: Option<String>,
+    pub changelog: Option<PathBuf>,
+}
+
+/// 一个仓库最近一次同步的结果, 时间均为 Unix 秒
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoState {
+    pub path: String,
+    pub interval: u64,
+    /// `ok` 或错误类别, 尚未同步时为 `None`
+    pub last_result: Option<String>,
+    pub last_error: Option<String>,
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_duration_ms: Option<u64>,
+    /// 连续失败次数
+    pub failures: u32,
+    pub next_run: u64,
+    /// 已经在队列中或正在同步
+    pub running: bool,
+    /// 超过两个同步间隔没有成功
+    pub stale: bool,
+}
+
+impl RepoState {
+    fn new(repo: &Repo, interval: u64, now: u64) -> RepoState {
+        RepoState {
+            path: repo.path.clone(),
+            interval: repo.options.interval.unwrap_or(interval),
+            last_result: None,
+            last_error: None,
+            last_attempt: None,
+            last_success: None,
+            last_duration_ms: None,
+            failures: 0,
+            next_run: now,
+            running: false,
+            stale: false,
+        }
+    }
+
+    fn record(&mut self, finished: &Finished<Result<SyncReport, SyncError>>, now: u64) {
+        self.last_attempt = Some(now);
+        self.last_duration_ms = Some(finished.duration.as_millis() as u64);
+        let error = match &finished.result {
+            Ok(Ok(report)) => report
+                .hooks
+                .as_ref()
+                .and_then(|h| h.failed())
+                .map(|r| ("hook", format!("同步后命令失败: {}", r.command))),
+            Ok(Err(e)) => Some((e.kind(), e.to_string())),
+            Err(panic) => Some(("panic", panic.clone())),
+        };
+        match error {
+            None => {
+                self.last_result = Some("ok".to_string());
+                self.last_error = None;
+                self.last_success = Some(now);
+                self.failures = 0;
+                self.next_run = now + self.interval;
+                println!("[{}] 同步成功, {} 秒后再次同步", self.path, self.interval);
+            }
+            Some((kind, message)) => {
+                self.last_result = Some(kind.to_string());
+                self.failures += 1;
+                let delay = backoff(self.failures);
+                self.next_run = now + delay;
+                println!(
+                    "[{}] 第 {} 次失败 [{}]: {}, {} 秒后重试",
+                    self.path, self.failures, kind, message, delay
+                );
+                self.last_error = Some(message);
+            }
+        }
+    }
+
+    /// 策略没有选中时跳过本轮, 按正常间隔再询问; `error` 为策略执行出错的原因
+    fn skip(&mut self, now: u64, error: Option<String>) {
+        self.last_attempt = Some(now);
+        self.last_result = Some(if error.is_some() { "policy" } else { "skipped" }.to_string());
+        match &error {
+            Some(e) => println!("[{}] {}, {} 秒后再次询问", self.path, e, self.interval),
+            None => println!("[{}] 策略跳过, {} 秒后再次询问", self.path, self.interval),
+        }
+        self.last_error = error;
+        self.next_run = now + self.interval;
+    }
+
+    fn refresh(&mut self, now: u64) {
+        self.stale = match self.last_success {
+            Some(t) => now.saturating_sub(t) > self.interval * 2,
+            None => self.last_attempt.is_some(),
+        };
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 指数退避, 上下浮动 20% 避免多个仓库同时重试
+fn backoff(failures: u32) -> u64 {
+    let exp = failures.saturating_sub(1).min(16);
+    let secs = BACKOFF_BASE.saturating_mul(1 << exp).min(BACKOFF_MAX);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now());
+    let permille = 800 + hasher.finish() % 401;
+    secs * permille / 1000
+}
+
+fn snapshot(states: &[RepoState]) -> Vec<RepoState> {
+    let mut states = states.to_vec();
+    let now = now();
+    for s in &mut states {
+        s.refresh(now);
+    }
+    states
+}
+
+/// 先写临时文件再改名, 读取方不会看到写了一半的内容
+fn write_state(path: &Path, states: &[RepoState]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(states)?)?;
+    fs::rename(tmp, path)
+}
+
+fn serve_state(listener: TcpListener, states: Arc<Mutex<Vec<RepoState>>>) {
+    for stream in listener.incoming().flatten() {
+        let states = states.clone();
+        thread::spawn(move || {
+            if let Err(e) = respond(stream, &states) {
+                eprintln!("daemon: 响应状态请求失败: {}", e);
+            }
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, states: &Mutex<Vec<RepoState>>) -> io::Result<()> {
+    // 只读取请求头, 任何路径都返回全部仓库的状态
+    let reader = BufReader::new(&mut stream);
+    for line in reader.lines() {
+        if line?.is_empty() {
+            break;
+        }
+    }
+    let body = {
+        let states = states.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_string_pretty(&snapshot(&states))?
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+/// 到期等待同步的仓库, 镜像可能是其它仓库的克隆来源, 先于检出取出
+#[derive(Default)]
+struct Due {
+    mirrors: VecDeque<usize>,
+    checkouts: VecDeque<usize>,
+    /// 按了 Ctrl-C, 工作线程做完手头的仓库后退出
+    closed: bool,
+}
+
+/// 调度线程和工作线程之间的队列. 工作线程同步完一个仓库后也通知调度线程,
+/// 让它按新的 `next_run` 重新计算等待时间
+#[derive(Default)]
+struct Queue {
+    due: Mutex<Due>,
+    /// 有仓库入队或关闭时通知工作线程
+    pushed: Condvar,
+    /// 有仓库同步完成时通知调度线程
+    finished: Condvar,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, Due> {
+        self.due.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, i: usize, mirror: bool) {
+        let mut due = self.lock();
+        if mirror {
+            due.mirrors.push_back(i);
+        } else {
+            due.checkouts.push_back(i);
+        }
+        self.pushed.notify_one();
+    }
+
+    /// 取出下一个仓库, 关闭后返回 `None`
+    fn pop(&self) -> Option<usize> {
+        let mut due = self.lock();
+        loop {
+            if due.closed {
+                return None;
+            }
+            if let Some(i) = due
+                .mirrors
+                .pop_front()
+                .or_else(|| due.checkouts.pop_front())
+            {
+                return Some(i);
+            }
+            due = self.pushed.wait(due).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// 等到有仓库同步完成或超过 `timeout`
+    fn wait(&self, timeout: Duration) {
+        let due = self.lock();
+        let _ = self.finished.wait_timeout(due, timeout);
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.pushed.notify_all();
+    }
+}
+
+/// 常驻运行: 每个仓库按自己的间隔同步, 失败后按指数退避重试. 到期的仓库进入队列,
+/// 由 `concurrency` 个工作线程各自取出同步, 慢的仓库只占住一个工作线程, 不耽误其它
+/// 仓库的间隔. 按 Ctrl-C 后等进行中的同步结束, 写完状态再返回; 除此之外只在启动失败时返回
+pub fn run(repos: &[Repo], ctx: &SyncContext, opts: Options) -> io::Result<()> {
+    let start = now();
+    let states = Arc::new(Mutex::new(
+        repos
+            .iter()
+            .map(|r| RepoState::new(r, opts.interval, start))
+            .collect::<Vec<_>>(),
+    ));
+    if let Some(addr) = &opts.listen {
+        let listener = TcpListener::bind(addr)?;
+        println!("daemon: 状态服务地址 http://{}", listener.local_addr()?);
+        let states = states.clone();
+        thread::spawn(move || serve_state(listener, states));
+    }
+
+    let queue = Queue::default();
+    let workers = opts.concurrency.clamp(1, repos.len().max(1));
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                while let Some(i) = queue.pop() {
+                    let finished = pool::measure(|| repos[i].check(ctx));
+                    finish(&repos[i], i, &finished, &states, &opts);
+                    queue.finished.notify_all();
+                }
+            });
+        }
+        schedule(repos, ctx, &opts, &states, &queue);
+        queue.close();
+    });
+    println!("daemon: 已取消");
+    Ok(())
+}
+
+/// 把到期且空闲的仓库放入队列, 直到按了 Ctrl-C
+fn schedule(
+    repos: &[Repo],
+    ctx: &SyncContext,
+    opts: &Options,
+    states: &Mutex<Vec<RepoState>>,
+    queue: &Queue,
+) {
+    // 等待期间每隔这么久检查一次取消标记
+    const TICK: Duration = Duration::from_millis(200);
+    while !cancel::requested() {
+        let now = now();
+        let (due, next): (Vec<usize>, Option<u64>) = {
+            let states = states.lock().unwrap_or_else(|e| e.into_inner());
+            let idle = || (0..states.len()).filter(|&i| !states[i].running);
+            let due = idle().filter(|&i| states[i].next_run <= now).collect();
+            (due, idle().map(|i| states[i].next_run).min())
+        };
+        // 策略脚本可能较慢, 不在持有状态锁时执行
+        let (due, skipped): (Vec<usize>, Vec<(usize, Option<String>)>) = match &ctx.policy {
+            None => (due, vec![]),
+            Some(policy) => {
+                let mut selected = vec![];
+                let mut skipped = vec![];
+                for i in due {
+                    match policy.should_sync(&repos[i]) {
+                        Ok(true) => selected.push(i),
+                        Ok(false) => skipped.push((i, None)),
+                        Err(e) => skipped.push((i, Some(e.to_string()))),
+                    }
+                }
+                (selected, skipped)
+            }
+        };
+        if !due.is_empty() || !skipped.is_empty() {
+            let mut states = states.lock().unwrap_or_else(|e| e.into_inner());
+            for (i, error) in skipped {
+                states[i].skip(now, error);
+            }
+            for &i in &due {
+                states[i].running = true;
+            }
+        }
+        for &i in &due {
+            queue.push(i, repos[i].options.mirror);
+        }
+        if due.is_empty() {
+            let wait = next.map_or(opts.interval, |t| t.saturating_sub(now)).max(1);
+            queue.wait(Duration::from_secs(wait).min(TICK));
+        }
+    }
+}
+
+/// 记录一个仓库的同步结果, 追加变更日志并写状态文件. 文件在持有状态锁时写入,
+/// 多个工作线程不会同时写同一个临时文件
+fn finish(
+    repo: &Repo,
+    i: usize,
+    finished: &Finished<Result<SyncReport, SyncError>>,
+    states: &Mutex<Vec<RepoState>>,
+    opts: &Options,
+) {
+    let mut states = states.lock().unwrap_or_else(|e| e.into_inner());
+    states[i].record(finished, now());
+    states[i].running = false;
+    if let (
+        Some(path),
+        Ok(Ok(SyncReport {
+            changelog: Some(log),
+            ..
+        })),
+    ) = (&opts.changelog, &finished.result)
+    {
+        if let Err(e) = crate::write_changelog(path, iter::once((repo.path.as_str(), log))) {
+            eprintln!("写入变更日志 {} 失败: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = &opts.state {
+        if let Err(e) = write_state(path, &snapshot(&states)) {
+            eprintln!("写入状态文件 {} 失败: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_failure_waits_about_base() {
+        for _ in 0..50 {
+            let delay = backoff(1);
+            assert!((24..=36).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        for failures in 1..=7 {
+            let secs = BACKOFF_BASE << (failures - 1);
+            let delay = backoff(failures);
+            assert!(
+                secs * 8 / 10 <= delay && delay <= secs * 12 / 10,
+                "{}: {}",
+                failures,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for failures in [8, 17, 100, u32::MAX] {
+            let delay = backoff(failures);
+            assert!(delay <= BACKOFF_MAX * 12 / 10, "{}: {}", failures, delay);
+            assert!(delay >= BACKOFF_MAX * 8 / 10, "{}: {}", failures, delay);
+        }
+    }
+}
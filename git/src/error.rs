@@ -0,0 +1,251 @@
+use crate::merge::Conflict;
+use git2::{ErrorClass, ErrorCode};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// 同步过程中的错误, 调用方可以按类型决定重试, 报告或跳过
+#[derive(Debug)]
+pub enum SyncError {
+    /// 本地仓库无法打开
+    Open {
+        path: PathBuf,
+        source: git2::Error,
+    },
+    /// 认证失败 (凭据被拒绝或证书校验失败)
+    Auth(git2::Error),
+    /// 网络或传输层错误
+    Network(git2::Error),
+    /// 远端仓库, 分支或引用不存在
+    NotFound(git2::Error),
+    /// 合并产生冲突, 列出每个冲突文件及三方 blob id
+    MergeConflict {
+        conflicts: Vec<Conflict>,
+    },
+    /// 工作区有本地修改且策略为 abort, 列出被修改的文件
+    DirtyTree {
+        paths: Vec<String>,
+    },
+    /// 拉取后暂存的本地修改无法干净地重新应用, 修改仍保留在 stash@{0} 中
+    StashConflict {
+        paths: Vec<String>,
+    },
+    /// 传输超过时间上限, 或超过 `limit` 没有任何进展 (`stalled`), 被看门狗中止
+    Timeout {
+        limit: Duration,
+        stalled: bool,
+    },
+    /// 按了 Ctrl-C, 传输被中止或仓库没有开始同步
+    Cancelled,
+    /// 策略脚本加载或执行出错, 或返回了无法识别的值
+    Policy(String),
+    /// 策略脚本拒绝了拉取到的 `commits` 个新提交, 分支保持不变
+    Rejected {
+        commits: usize,
+        reason: String,
+    },
+    /// 清单中的配置互相矛盾, 比如开启了 `shared` 却没有配置 `cache`
+    Config(String),
+    /// 其它 libgit2 错误
+    Git(git2::Error),
+    Io(io::Error),
+}
+
+impl SyncError {
+    /// 简短的错误类别, 用于汇总输出
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncError::Open { .. } => "open",
+            SyncError::Auth(_) => "auth",
+            SyncError::Network(_) => "network",
+            SyncError::NotFound(_) => "not-found",
+            SyncError::MergeConflict { .. } => "merge-conflict",
+            SyncError::DirtyTree { .. } => "dirty-tree",
+            SyncError::StashConflict { .. } => "stash-conflict",
+            SyncError::Timeout { .. } => "timeout",
+            SyncError::Cancelled => "cancelled",
+            SyncError::Policy(_) => "policy",
+            SyncError::Rejected { .. } => "rejected",
+            SyncError::Config(_) => "config",
+            SyncError::Git(_) => "git",
+            SyncError::Io(_) => "io",
+        }
+    }
+
+    /// 是否是值得重试的临时错误: 超时, 传输停滞, 连接被重置或拒绝, 提前断开以及 HTTP 5xx/408/429.
+    /// 认证失败, 不存在, 冲突等永久错误重试也不会成功
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SyncError::Network(e) | SyncError::Git(e) => is_transient_message(e.message()),
+            SyncError::Timeout { .. } => true,
+            SyncError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    pub fn open(path: impl Into<PathBuf>, source: git2::Error) -> SyncError {
+        SyncError::Open {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+/// libgit2 的网络错误只有文本可以区分, 按套接字和 HTTP 层的报错信息判断
+fn is_transient_message(message: &str) -> bool {
+    const MARKERS: &[&str] = &[
+        "timed out",
+        "timeout",
+        "connection reset",
+        "connection refused",
+        "connection aborted",
+        "broken pipe",
+        "early eof",
+        "unexpected eof",
+        "temporary failure",
+        "network is unreachable",
+        "failed to connect",
+        "could not read from socket",
+        "could not write to socket",
+        "syscall failure",
+    ];
+    let message = message.to_ascii_lowercase();
+    if MARKERS.iter().any(|m| message.contains(m)) {
+        return true;
+    }
+    // "unexpected http status code: 503"
+    let status = message
+        .split("status code:")
+        .nth(1)
+        .and_then(|rest| rest.trim().get(..3))
+        .and_then(|code| code.parse::<u16>().ok());
+    matches!(status, Some(500..=599 | 408 | 429))
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Open { path, source } => {
+                write!(f, "打开仓库 {} 失败: {}", path.display(), source.message())
+            }
+            SyncError::Auth(e) => write!(f, "认证失败: {}", e.message()),
+            SyncError::Network(e) => write!(f, "网络错误: {}", e.message()),
+            SyncError::NotFound(e) => write!(f, "未找到: {}", e.message()),
+            SyncError::MergeConflict { conflicts } => {
+                write!(f, "合并冲突 ({} 个文件):", conflicts.len())?;
+                for c in conflicts {
+                    write!(f, " {}", c)?;
+                }
+                Ok(())
+            }
+            SyncError::DirtyTree { paths } => {
+                write!(
+                    f,
+                    "工作区有 {} 个文件被修改: {}",
+                    paths.len(),
+                    paths.join(", ")
+                )
+            }
+            SyncError::StashConflict { paths } => write!(
+                f,
+                "本地修改与拉取内容冲突, 已保留在 stash@{{0}}: {}",
+                paths.join(", ")
+            ),
+            SyncError::Timeout {
+                limit,
+                stalled: true,
+            } => write!(f, "传输超过 {:?} 没有进展, 已中止", limit),
+            SyncError::Timeout { limit, .. } => write!(f, "传输超过 {:?}, 已中止", limit),
+            SyncError::Cancelled => write!(f, "已取消"),
+            SyncError::Policy(message) => write!(f, "策略脚本出错: {}", message),
+            SyncError::Rejected { commits, reason } => {
+                write!(f, "策略拒绝了 {} 个新提交: {}", commits, reason)
+            }
+            SyncError::Config(message) => write!(f, "配置错误: {}", message),
+            SyncError::Git(e) => write!(f, "{}", e.message()),
+            SyncError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::Open { source, .. } => Some(source),
+            SyncError::Auth(e)
+            | SyncError::Network(e)
+            | SyncError::NotFound(e)
+            | SyncError::Git(e) => Some(e),
+            SyncError::Io(e) => Some(e),
+            SyncError::MergeConflict { .. }
+            | SyncError::DirtyTree { .. }
+            | SyncError::StashConflict { .. }
+            | SyncError::Timeout { .. }
+            | SyncError::Cancelled
+            | SyncError::Policy(_)
+            | SyncError::Rejected { .. }
+            | SyncError::Config(_) => None,
+        }
+    }
+}
+
+impl From<git2::Error> for SyncError {
+    fn from(e: git2::Error) -> Self {
+        match (e.code(), e.class()) {
+            (ErrorCode::Auth | ErrorCode::Certificate, _) => SyncError::Auth(e),
+            (ErrorCode::NotFound, _) => SyncError::NotFound(e),
+            (_, ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssl | ErrorClass::Ssh) => {
+                SyncError::Network(e)
+            }
+            _ => SyncError::Git(e),
+        }
+    }
+}
+
+impl From<io::Error> for SyncError {
+    fn from(e: io::Error) -> Self {
+        SyncError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_status_codes() {
+        for code in [500, 502, 503, 599, 408, 429] {
+            let message = format!("unexpected http status code: {}", code);
+            assert!(is_transient_message(&message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn permanent_status_codes() {
+        for code in [400, 401, 403, 404, 407, 422, 600] {
+            let message = format!("unexpected http status code: {}", code);
+            assert!(!is_transient_message(&message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn status_number_elsewhere_in_message() {
+        assert!(!is_transient_message(
+            "reference 'refs/heads/release-503' not found"
+        ));
+        assert!(!is_transient_message(
+            "unexpected http status code: 404 (retry after 503)"
+        ));
+        assert!(is_transient_message("Connection reset by peer"));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::cancel;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 每个命令保留的 stdout/stderr 上限, 超出时只保留末尾
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// 同步后在仓库目录中执行的命令, 例如构建或重启服务
+///
+/// ```toml
+/// [repos.options.hooks]
+/// post_sync = ["cargo build --release", "systemctl restart app"]
+/// timeout = 600
+/// rollback = true
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// HEAD 变化 (包括首次克隆) 后依次执行, 一个失败后不再执行后面的
+    pub post_sync: Vec<String>,
+    /// 每个命令的超时 (秒), 超时后结束该命令启动的所有进程
+    pub timeout: u64,
+    /// 命令失败时把仓库回滚到同步前的 HEAD, 子模块不回滚
+    pub rollback: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            post_sync: vec![],
+            timeout: 300,
+            rollback: false,
+        }
+    }
+}
+
+/// 一个命令的执行结果
+#[derive(Debug, Clone)]
+pub struct HookResult {
+    pub command: String,
+    /// 退出码, 被信号结束或超时时为 `None`
+    pub code: Option<i32>,
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    /// 无法启动等错误
+    pub error: Option<String>,
+}
+
+impl HookResult {
+    pub fn success(&self) -> bool {
+        self.error.is_none() && !self.timed_out && self.code == Some(0)
+    }
+}
+
+/// 同步后命令的整体结果
+#[derive(Debug, Clone, Default)]
+pub struct HookReport {
+    pub results: Vec<HookResult>,
+    /// 回滚到的提交, 没有回滚时为 `None`
+    pub rolled_back: Option<String>,
+    /// 需要回滚却没有回滚成功的原因
+    pub rollback_error: Option<String>,
+}
+
+impl HookReport {
+    pub fn failed(&self) -> Option<&HookResult> {
+        self.results.iter().find(|r| !r.success())
+    }
+}
+
+impl Hooks {
+    /// 在 `dir` 中依次执行 `post_sync`, `env` 附加到每个命令的环境变量中
+    pub fn run(&self, dir: &Path, env: &[(&str, String)]) -> Vec<HookResult> {
+        let timeout = Duration::from_secs(self.timeout);
+        let mut results = vec![];
+        for command in &self.post_sync {
+            let result = run_one(command, dir, env, timeout);
+            let success = result.success();
+            results.push(result);
+            if !success {
+                break;
+            }
+        }
+        results
+    }
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        // 单独的进程组, 超时或取消时可以一起结束命令启动的子进程
+        cmd.process_group(0);
+        cmd
+    }
+}
+
+/// 结束命令及其启动的子进程
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // SAFETY: 只是向 shell() 创建的进程组发送信号
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill();
+}
+
+/// 在后台线程中读取管道, 只保留末尾 MAX_OUTPUT 字节. 命令启动的后台进程 (例如重启的
+/// 服务) 可能在命令退出后继续占用管道, 所以取结果时只短暂等待管道关闭
+struct Capture {
+    tail: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Capture {
+    fn start(pipe: Option<impl Read + Send + 'static>) -> Capture {
+        let tail = Arc::new(Mutex::new(vec![]));
+        let (tx, done) = mpsc::channel();
+        let buffer = tail.clone();
+        thread::spawn(move || {
+            if let Some(mut pipe) = pipe {
+                let mut buf = [0; 8192];
+                while let Ok(n) = pipe.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    let mut tail = buffer.lock().unwrap_or_else(|e| e.into_inner());
+                    tail.extend_from_slice(&buf[..n]);
+                    if tail.len() > MAX_OUTPUT {
+                        let excess = tail.len() - MAX_OUTPUT;
+                        tail.drain(..excess);
+                    }
+                }
+            }
+            let _ = tx.send(());
+        });
+        Capture { tail, done }
+    }
+
+    fn finish(self, deadline: Instant) -> String {
+        let _ = self
+            .done
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        let tail = self.tail.lock().unwrap_or_else(|e| e.into_inner());
+        String::from_utf8_lossy(&tail).into_owned()
+    }
+}
+
+fn run_one(command: &str, dir: &Path, env: &[(&str, String)], timeout: Duration) -> HookResult {
+    let start = Instant::now();
+    let mut result = HookResult {
+        command: command.to_string(),
+        code: None,
+        timed_out: false,
+        duration: Duration::ZERO,
+        stdout: String::new(),
+        stderr: String::new(),
+        error: None,
+    };
+    let spawned = shell(command)
+        .current_dir(dir)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            result.error = Some(format!("无法启动: {}", e));
+            return result;
+        }
+    };
+    let stdout = Capture::start(child.stdout.take());
+    let stderr = Capture::start(child.stderr.take());
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if start.elapsed() > timeout => {
+                result.timed_out = true;
+                kill(&mut child);
+                break child.wait();
+            }
+            Ok(None) if cancel::requested() => {
+                result.error = Some("已取消".to_string());
+                kill(&mut child);
+                break child.wait();
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => break Err(e),
+        }
+    };
+    match status {
+        Ok(status) => result.code = status.code(),
+        Err(e) => result.error = Some(e.to_string()),
+    }
+    result.duration = start.elapsed();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    result.stdout = stdout.finish(deadline);
+    result.stderr = stderr.finish(deadline);
+    result
+}
+
+/// 命令输出的最后几行, 用于汇总时提示失败原因
+pub fn last_lines(output: &str, n: usize) -> Vec<&str> {
+    let lines: Vec<&str> = output.lines().filter(|l| !l.trim().is_empty()).collect();
+    lines[lines.len().saturating_sub(n)..].to_vec()
+}
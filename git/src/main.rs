@@ -1,356 +1,54 @@
-use git2::build::{CheckoutBuilder, CloneLocal, RepoBuilder};
-use git2::{self, Cred, Time};
-use git2::{
-    Commit, Error, FetchOptions, ObjectType, RemoteCallbacks, Repository, RepositoryInitOptions,
-    ResetType,
-};
+mod bench;
+mod cache;
+mod cancel;
+mod changelog;
+mod cli;
+mod credentials;
+mod daemon;
+mod error;
+mod hooks;
+mod manifest;
+mod merge;
+mod pin;
+mod policy;
+mod pool;
+mod progress;
+mod repo;
+mod retry;
+mod sparse;
+mod status;
+
+use cache::ObjectCache;
+use changelog::Changelog;
+use clap::Parser;
+use cli::{BenchTarget, Cli, Command, OutputFormat};
+use credentials::Credentials;
+use error::SyncError;
+use git2::{self, Repository};
+use manifest::Manifest;
+use merge::{MergeOutcome, PullMode};
+use policy::{PolicyHost, PolicyScript};
+use progress::Progress;
+use repo::{DirtyPolicy, Repo, RepoOptions, SyncContext, SyncReport};
 use rust_embed::RustEmbed;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::io::{BufRead, BufReader, Read, Write};
+use status::RepoStatus;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::{TcpListener, TcpStream};
 use std::path::Path;
-use std::thread::Thread;
-use std::time::Instant;
-use std::{env, io, process, thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{process, thread};
 use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3;
 
-fn do_fetch<'a>(
-    repo: &'a git2::Repository,
-    refs: &[&str],
-    remote: &'a mut git2::Remote,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
-    let mut cb = git2::RemoteCallbacks::new();
-
-    // Print out our transfer progress.
-    cb.transfer_progress(|stats| {
-        if stats.received_objects() == stats.total_objects() {
-            print!(
-                "Resolving deltas {}/{}\r",
-                stats.indexed_deltas(),
-                stats.total_deltas()
-            );
-        } else if stats.total_objects() > 0 {
-            print!(
-                "Received {}/{} objects ({}) in {} bytes\r",
-                stats.received_objects(),
-                stats.total_objects(),
-                stats.indexed_objects(),
-                stats.received_bytes()
-            );
-        }
-        io::stdout().flush().unwrap();
-        true
-    });
-
-    let mut fo = git2::FetchOptions::new();
-    fo.remote_callbacks(cb);
-    // Always fetch all tags.
-    // Perform a download and also update tips
-    fo.download_tags(git2::AutotagOption::All);
-    println!("Fetching {} for repo", remote.name().unwrap());
-    remote.fetch(refs, Some(&mut fo), None)?;
-
-    // If there are local objects (we got a thin pack), then tell the user
-    // how many objects we saved from having to cross the network.
-    let stats = remote.stats();
-    if stats.local_objects() > 0 {
-        println!(
-            "\rReceived {}/{} objects in {} bytes (used {} local \
-             objects)",
-            stats.indexed_objects(),
-            stats.total_objects(),
-            stats.received_bytes(),
-            stats.local_objects()
-        );
-    } else {
-        println!(
-            "\rReceived {}/{} objects in {} bytes",
-            stats.indexed_objects(),
-            stats.total_objects(),
-            stats.received_bytes()
-        );
-    }
-
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    let c = repo.reference_to_annotated_commit(&fetch_head)?;
-    // println!("download-----------------------------------");
-    // remote.download(refs, Some(&mut fo))?;
-
-    // let stats = remote.stats();
-    // if stats.local_objects() > 0 {
-    //     println!(
-    //         "\rReceived {}/{} objects in {} bytes (used {} local \
-    //          objects)",
-    //         stats.indexed_objects(),
-    //         stats.total_objects(),
-    //         stats.received_bytes(),
-    //         stats.local_objects()
-    //     );
-    // } else {
-    //     println!(
-    //         "\rReceived {}/{} objects in {} bytes",
-    //         stats.indexed_objects(),
-    //         stats.total_objects(),
-    //         stats.received_bytes()
-    //     );
-    // }
-    Ok(c)
-}
-
-fn fast_forward(
-    repo: &Repository,
-    lb: &mut git2::Reference,
-    rc: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
-    let name = match lb.name() {
-        Some(s) => s.to_string(),
-        None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
-    };
-    let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
-    lb.set_target(rc.id(), &msg)?;
-    repo.set_head(&name)?;
-    repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
-            // For some reason the force is required to make the working directory actually get updated
-            // I suspect we should be adding some logic to handle dirty working directory states
-            // but this is just an example so maybe not.
-            .force(),
-    ))?;
-    Ok(())
-}
-
-fn normal_merge(
-    repo: &Repository,
-    local: &git2::AnnotatedCommit,
-    remote: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
-    let local_tree = repo.find_commit(local.id())?.tree()?;
-    let remote_tree = repo.find_commit(remote.id())?.tree()?;
-    let ancestor = repo
-        .find_commit(repo.merge_base(local.id(), remote.id())?)?
-        .tree()?;
-    let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
-
-    if idx.has_conflicts() {
-        println!("Merge conflicts detected...");
-        repo.checkout_index(Some(&mut idx), None)?;
-        return Ok(());
-    }
-    let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
-    // now create the merge commit
-    let msg = format!("Merge: {} into {}", remote.id(), local.id());
-    let sig = repo.signature()?;
-    let local_commit = repo.find_commit(local.id())?;
-    let remote_commit = repo.find_commit(remote.id())?;
-    // Do our merge commit and set current branch head to that commit.
-    let _merge_commit = repo.commit(
-        Some("HEAD"),
-        &sig,
-        &sig,
-        &msg,
-        &result_tree,
-        &[&local_commit, &remote_commit],
-    )?;
-    // Set working tree to match head.
-    repo.checkout_head(None)?;
-    Ok(())
-}
-
-fn do_merge<'a>(
-    repo: &'a Repository,
-    remote_branch: &str,
-    fetch_commit: git2::AnnotatedCommit<'a>,
-) -> Result<(), git2::Error> {
-    // 1. do a merge analysis
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-    // 2. Do the appropriate merge
-    if analysis.0.is_fast_forward() {
-        println!("Doing a fast forward");
-        // do a fast forward
-        let refname = format!("refs/heads/{}", remote_branch);
-        match repo.find_reference(&refname) {
-            Ok(mut r) => {
-                fast_forward(repo, &mut r, &fetch_commit)?;
-            }
-            Err(_) => {
-                // The branch doesn't exist so just set the reference to the
-                // commit directly. Usually this is because you are pulling
-                // into an empty repository.
-                repo.reference(
-                    &refname,
-                    fetch_commit.id(),
-                    true,
-                    &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
-                )?;
-                repo.set_head(&refname)?;
-                repo.checkout_head(Some(
-                    git2::build::CheckoutBuilder::default()
-                        .allow_conflicts(true)
-                        .conflict_style_merge(true)
-                        .force(),
-                ))?;
-            }
-        };
-    } else if analysis.0.is_normal() {
-        // do a normal merge
-        let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
-    } else {
-        println!("Nothing to do...");
-    }
-    Ok(())
-}
-
-struct Repo {
-    url: String,
-    path: String,
-    branch: String,
-}
-
-impl Repo {
-    fn reset(&self, path: &Path) {
-        let repo = match Repository::open(path) {
-            Ok(repo) => repo,
-            Err(e) => panic!("Failed to open: {}", e),
-        };
-        repo.reset(
-            &repo.revparse_single("HEAD").unwrap(),
-            ResetType::Hard,
-            None,
-        )
-        .unwrap();
-    }
-
-    fn clone(&self) {
-        let mut rb = RepoBuilder::new();
-        let mut fo = FetchOptions::new();
-        let mut rc = RemoteCallbacks::new();
-        println!("开始下载");
-        // rc.transfer_progress(|p| {
-        //     println!(
-        //         "总对象数: {}, 增量对象: {}, 已进行哈希处理: {}, 已经行哈希处理增量: {}, 已下载对象: {}, 已注入本地对象: {}, 已接受包: {}",
-        //         p.total_objects(),
-        //         p.total_deltas(),
-        //         p.indexed_objects(),
-        //         p.indexed_deltas(),
-        //         p.received_objects(),
-        //         p.local_objects(),
-        //         p.received_bytes()
-        //     );
-        //     true
-        // });
-        rc.credentials(|_url, username_from_url, _allowed_types| {
-            println!("_url:{:#?}", _url);
-            println!("username_from_url:{:#?}", username_from_url);
-            println!("_allowed_types:{:#?}", _allowed_types);
-            Cred::userpass_plaintext("13433001217", "qwe513521qq")
-        });
-        fo.remote_callbacks(rc);
-        let rp = match rb
-            .fetch_options(fo)
-            // .clone_local(CloneLocal::Auto)
-            .clone(&self.url, &self.path.as_ref())
-        {
-            Ok(repo) => repo,
-            Err(e) => panic!("init 失败: {}", e),
-        };
-        // let repo = match Repository::clone(&self.url, &self.path) {
-        //     Ok(repo) => repo,
-        //     Err(e) => panic!("failed to init: {}", e),
-        // };
-    }
-
-    fn find_last_commit<'repo>(&self, repo: &'repo Repository) -> Result<Commit<'repo>, Error> {
-        let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
-        match obj.into_commit() {
-            Ok(c) => Ok(c),
-            _ => Err(Error::from_str("commit error")),
-        }
-    }
-
-    fn pull(&self, path: &Path) -> Result<(), Error> {
-        let repo = Repository::open(path)?;
-        let mut remote = repo.find_remote("origin")?;
-        let fetch_commit = do_fetch(&repo, &[&self.branch], &mut remote)?;
-        let _ = do_merge(&repo, &self.branch, fetch_commit);
-
-        // let repo = Repository::open(path)?;
-
-        // println!("is_worktree: {:#?}", repo.is_bare());
-
-        // let mut f = FetchOptions::new();
-        // let mut rc = RemoteCallbacks::new();
-        // println!("开始下载");
-        // rc.transfer_progress(|p| {
-        //     println!(
-        //         "总对象数: {}, 增量对象: {}, 已进行哈希处理: {}, 已经行哈希处理增量: {}, 已下载对象: {}, 已注入本地对象: {}, 已接受包: {}",
-        //         p.total_objects(),
-        //         p.total_deltas(),
-        //         p.indexed_objects(),
-        //         p.indexed_deltas(),
-        //         p.received_objects(),
-        //         p.local_objects(),
-        //         p.received_bytes()
-        //     );
-        //     true
-        // });
-        // rc.update_tips(|s, o1, o2| {
-        //     println!("s: {:#?}, o1: {:#?}, o2: {:#?}", s, o1, o2);
-        //     true
-        // });
-        // f.remote_callbacks(rc);
-
-        // repo.find_remote("origin")?
-        //     .fetch(&[&self.branch], Some(&mut f), None)?;
-
-        // let last_commit = self.find_last_commit(&repo)?;
-        // let reference = repo.find_reference("FETCH_HEAD")?;
-        // let fetched_commit = reference.peel_to_commit()?;
-        // let index =
-        //     repo.merge_commits(&last_commit, &fetched_commit, Some(&MergeOptions::new()))?;
-        Ok(())
-        // Ok(index);
-
-        // let repo = Repository::open(path)?;
-        //
-        // repo.find_remote("origin")?
-        //     .fetch(&[self.branch], None, None)?;
-        //
-        // let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        // let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-        // let analysis = repo.merge_analysis(&[&fetch_commit])?;
-        // if analysis.0.is_up_to_date() {
-        //     Ok(())
-        // } else if analysis.0.is_fast_forward() {
-        //     let refname = format!("refs/heads/{}", self.branch);
-        //     let mut reference = repo.find_reference(&refname)?;
-        //     reference.set_target(fetch_commit.id(), "Fast-Forward")?;
-        //     repo.set_head(&refname)?;
-        //     repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
-        // } else {
-        //     Err(Error::from_str("Fast-forward only!"))
-        // }
-    }
-
-    pub fn check(&self) {
-        let repo_path = Path::new(&self.path);
-
-        if !repo_path.exists() {
-            self.clone();
-            return;
-        }
-
-        if repo_path.exists() && repo_path.is_dir() {
-            self.reset(repo_path);
-            let idx = match self.pull(repo_path) {
-                Ok(idx) => idx,
-                Err(e) => panic!("Failed to pull: {}", e),
-            };
-        }
-    }
-}
+/// 有仓库同步或命令执行失败
+const EXIT_FAILURE: i32 = 1;
+/// 清单无法读取或解析 (参数错误由 clap 以 2 退出)
+const EXIT_CONFIG: i32 = 3;
+/// daemon 模式下默认的同步间隔 (秒)
+const DEFAULT_INTERVAL: u64 = 300;
+/// 按 Ctrl-C 取消 (128 + SIGINT)
+const EXIT_CANCELLED: i32 = 130;
 
 #[derive(Debug, RustEmbed)]
 #[folder = "dist/"]
@@ -360,12 +58,17 @@ fn handle_connection(mut stream: TcpStream) {
     let buf_reader = BufReader::new(&mut stream);
     let http_request: Vec<_> = buf_reader
         .lines()
-        .map(|result| result.unwrap())
+        .map_while(Result::ok)
         .take_while(|line| !line.is_empty())
         .collect();
 
-    let line = http_request.get(0).unwrap();
-    let arr: Vec<&str> = line.split(" ").collect();
+    let Some(line) = http_request.first() else {
+        return;
+    };
+    let arr: Vec<&str> = line.split(' ').collect();
+    if arr.len() < 2 {
+        return;
+    }
 
     let mut path = arr[1];
 
@@ -373,16 +76,27 @@ fn handle_connection(mut stream: TcpStream) {
         path = "/index.html";
     }
 
-    path = &path[1..];
+    let Some(path) = path.strip_prefix('/') else {
+        if let Err(e) = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n") {
+            println!("响应失败: {}", e);
+        }
+        return;
+    };
 
     println!("path: {},{:#?}", path, arr[1]);
 
-    let binding = Asset::get(path).unwrap();
-    let html = std::str::from_utf8(binding.data.as_ref());
-
-    let response = "HTTP/1.1 200 OK\r\n\r\n".to_owned() + &html.unwrap();
+    let response = match Asset::get(path) {
+        Some(binding) => {
+            let mut response = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+            response.extend_from_slice(binding.data.as_ref());
+            response
+        }
+        None => b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
+    };
 
-    stream.write(response.as_bytes()).unwrap();
+    if let Err(e) = stream.write_all(&response) {
+        println!("响应失败: {}", e);
+    }
 }
 
 // 调用 Windows api
@@ -410,129 +124,529 @@ fn handle_connection(mut stream: TcpStream) {
 //     }
 // }
 
-// 运行js
-// fn run_js() {
-//
-// }
+/// 用工作线程池并行同步清单中的仓库, 最后打印每个仓库的结果汇总, 全部成功时返回 true
+fn sync(
+    manifest: &Manifest,
+    jobs: Option<usize>,
+    changelog: Option<&Path>,
+    policy: Option<PolicyHost>,
+    progress: Box<dyn Progress>,
+) -> bool {
+    let start = Instant::now();
+    let ctx = SyncContext {
+        creds: manifest.credentials(),
+        progress,
+        cache: manifest.cache.clone().map(ObjectCache::new),
+        policy,
+    };
+    let (selected, policy_failed) = select(&manifest.repos, ctx.policy.as_ref());
+    let concurrency = jobs
+        .or(manifest.concurrency)
+        .unwrap_or_else(pool::default_concurrency);
+    // 镜像可能是其它仓库的克隆来源, 先于其它仓库同步
+    let (mirrors, checkouts): (Vec<&Repo>, Vec<&Repo>) =
+        selected.into_iter().partition(|r| r.options.mirror);
+    let run = |repos: &[&Repo]| {
+        pool::run(
+            repos,
+            concurrency,
+            |repo| repo.check(&ctx),
+            |repo, finished| println!("[{}]: 耗时: {:?}", repo.path, finished.duration),
+        )
+    };
+    let mut results = run(&mirrors);
+    results.extend(run(&checkouts));
+
+    println!("\n同步结果:");
+    let (mut failed, mut submodules_failed, mut hooks_failed) = (0, 0, 0);
+    for (repo, finished) in mirrors.iter().chain(&checkouts).zip(&results) {
+        match &finished.result {
+            Ok(Ok(report)) => {
+                println!("  [成功] {} ({:?})", repo.path, finished.duration);
+                print_report(report);
+                submodules_failed += report
+                    .submodules
+                    .iter()
+                    .filter(|s| s.result.is_err())
+                    .count();
+                if report.hooks.as_ref().is_some_and(|h| h.failed().is_some()) {
+                    hooks_failed += 1;
+                }
+            }
+            Ok(Err(e)) => {
+                failed += 1;
+                println!(
+                    "  [失败:{}] {} ({:?}): {}",
+                    e.kind(),
+                    repo.path,
+                    finished.duration,
+                    e
+                );
+            }
+            Err(panic) => {
+                failed += 1;
+                println!(
+                    "  [失败] {} ({:?}): {}",
+                    repo.path, finished.duration, panic
+                );
+            }
+        }
+    }
+    for (repo, e) in &policy_failed {
+        failed += 1;
+        println!("  [失败:{}] {}: {}", e.kind(), repo.path, e);
+    }
+    println!(
+        "共 {} 个, 成功 {} 个, 失败 {} 个, 并发 {}, 总耗时: {:?}",
+        results.len() + policy_failed.len(),
+        results.len() + policy_failed.len() - failed,
+        failed,
+        concurrency,
+        start.elapsed()
+    );
+    if submodules_failed > 0 {
+        println!("子模块失败 {} 个", submodules_failed);
+    }
+    if hooks_failed > 0 {
+        println!("同步后命令失败 {} 个", hooks_failed);
+    }
+    if let Some(path) = changelog {
+        let reports = mirrors.iter().chain(&checkouts).zip(&results);
+        let entries = reports.filter_map(|(repo, finished)| match &finished.result {
+            Ok(Ok(SyncReport {
+                changelog: Some(log),
+                ..
+            })) => Some((repo.path.as_str(), log)),
+            _ => None,
+        });
+        if let Err(e) = write_changelog(path, entries) {
+            eprintln!("写入变更日志 {} 失败: {}", path.display(), e);
+        }
+    }
+    failed == 0 && hooks_failed == 0
+}
 
-fn main() {
-    // call_dll();
+/// 按策略脚本的 `shouldSync` 选出要同步的仓库, 没有策略时全部同步. 脚本对某个仓库
+/// 出错时只跳过该仓库, 和其它仓库的结果一起汇报
+fn select<'a>(
+    repos: &'a [Repo],
+    policy: Option<&PolicyHost>,
+) -> (Vec<&'a Repo>, Vec<(&'a Repo, SyncError)>) {
+    let Some(policy) = policy else {
+        return (repos.iter().collect(), vec![]);
+    };
+    let mut selected = vec![];
+    let mut failed = vec![];
+    for repo in repos {
+        match policy.should_sync(repo) {
+            Ok(true) => selected.push(repo),
+            Ok(false) => println!("[{}] 策略跳过", repo.path),
+            Err(e) => failed.push((repo, e)),
+        }
+    }
+    (selected, failed)
+}
 
-    // run_js()
+/// 每个有变化的仓库追加一行 JSON
+fn write_changelog<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = (&'a str, &'a Changelog)>,
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    for (repo, log) in entries {
+        let line = serde_json::json!({ "time": time, "repo": repo, "changelog": log });
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
 
-    // let listener = TcpListener::bind("127.0.0.1:3000").unwrap();
-    // for stream in listener.incoming() {
-    //     let stream = stream.unwrap();
-    //
-    //     thread::spawn(|| {
-    //         handle_connection(stream);
-    //     });
-    // }
-    // let start = Instant::now();
-    // // let mut v = vec![];
-    // for i in 2..3 {
-    //     // let a = thread::spawn(move || {
-    //     let name = format!("repo_{}", i);
-    //     let start = Instant::now();
-    //     let currencies = Repo {
-    //         url: "https://gitee.com/caretop/caretop7_next.git".to_string(),
-    //         // url: "https://gitee.com/openharmony/arkui_ace_engine.git".to_string(),
-    //         path: name.clone(),
-    //         branch: "master".to_string(),
-    //     };
-    //     currencies.check();
-    //     let duration = start.elapsed();
-    //     println!("[{}]: 耗时: {:?}", name, duration);
-    //     // });
-    //     // v.push(a);
-    // }
-    // // for e in v {
-    // //     e.join().unwrap();
-    // // }
-    // let duration = start.elapsed();
-    // println!("\n\n总耗时: {:?}", duration);
+/// 打印同步结果中的附加信息
+fn print_report(report: &SyncReport) {
+    if let Some(action) = report.dirty_action {
+        let action = match action {
+            DirtyPolicy::Abort => "未处理",
+            DirtyPolicy::Stash => "已暂存并恢复",
+            DirtyPolicy::Discard => "已丢弃",
+        };
+        println!(
+            "      本地修改{} ({} 个): {}",
+            action,
+            report.dirty.len(),
+            report.dirty.join(", ")
+        );
+    }
+    if let Some(from) = &report.switched_from {
+        println!("      切换分支, 原分支: {}", from);
+    }
+    let short = |id: &git2::Oid| id.to_string()[..8].to_string();
+    match &report.merge {
+        Some(MergeOutcome::FastForward { from, to }) => println!(
+            "      快进 {}..{}",
+            from.as_ref().map_or("-".to_string(), short),
+            short(to)
+        ),
+        Some(MergeOutcome::Merged { from, to, resolved }) => {
+            println!("      合并 {}..{}", short(from), short(to));
+            for c in resolved {
+                println!("      已自动解决冲突: {}", c);
+            }
+        }
+        Some(MergeOutcome::Rebased { from, to, replayed }) => println!(
+            "      变基 {}..{}, 重放 {} 个本地提交",
+            short(from),
+            short(to),
+            replayed
+        ),
+        Some(MergeOutcome::UpToDate) | None => {}
+    }
+    if let Some(log) = &report.changelog {
+        let stat = &log.stat;
+        println!(
+            "      {} 个新提交, 新增 {} 修改 {} 删除 {} 重命名 {} 个文件 (+{} -{})",
+            log.commits.len(),
+            stat.added,
+            stat.modified,
+            stat.deleted,
+            stat.renamed,
+            stat.insertions,
+            stat.deletions
+        );
+        const SHOWN: usize = 10;
+        for c in log.commits.iter().take(SHOWN) {
+            println!("        {} {} ({})", &c.id[..8], c.summary, c.author);
+        }
+        if log.commits.len() > SHOWN {
+            println!("        ... 还有 {} 个提交", log.commits.len() - SHOWN);
+        }
+    }
+    if let Some(pin) = &report.pin {
+        if pin.moved() {
+            println!(
+                "      固定版本移动到 {} ({}..{})",
+                pin.target,
+                pin.from.as_ref().map_or("-".to_string(), short),
+                short(&pin.to)
+            );
+        } else {
+            println!("      固定版本 {} ({})", pin.target, short(&pin.to));
+        }
+    }
+    if let Some(mirror) = &report.mirror {
+        println!(
+            "      镜像: 新增 {} 个, 更新 {} 个, 删除 {} 个引用",
+            mirror.created.len(),
+            mirror.updated.len(),
+            mirror.pruned.len()
+        );
+        for name in &mirror.pruned {
+            println!("      已删除: {}", name);
+        }
+    }
+    for sm in &report.submodules {
+        match &sm.result {
+            Ok(()) => println!("      子模块 {}: 已更新", sm.path),
+            Err(e) => println!("      子模块 {} [失败:{}]: {}", sm.path, e.kind(), e),
+        }
+    }
+    if let Some(hooks) = &report.hooks {
+        for r in &hooks.results {
+            let outcome = if r.success() {
+                "成功".to_string()
+            } else if r.timed_out {
+                "超时".to_string()
+            } else if let Some(e) = &r.error {
+                e.clone()
+            } else {
+                match r.code {
+                    Some(code) => format!("退出码 {}", code),
+                    None => "被信号结束".to_string(),
+                }
+            };
+            println!("      命令 `{}`: {} ({:?})", r.command, outcome, r.duration);
+            if !r.success() {
+                let output = if r.stderr.trim().is_empty() {
+                    &r.stdout
+                } else {
+                    &r.stderr
+                };
+                for line in hooks::last_lines(output, 5) {
+                    println!("        {}", line);
+                }
+            }
+        }
+        if let Some(id) = &hooks.rolled_back {
+            println!("      已回滚到 {}", &id[..8]);
+        }
+        if let Some(e) = &hooks.rollback_error {
+            println!("      回滚失败: {}", e);
+        }
+    }
+}
 
-    // let start = Instant::now();
-    // // let url = "https://gitee.com/openharmony/arkui_ace_engine.git";
-    // let url = "https://gitee.com/y_project/RuoYi-App.git";
-    // // let t1 = thread::spawn(move || {
-    // //     clone(url, "clone_dir");
-    // // });
-    // // let t2 = thread::spawn(move || {
-    // //     cmd(url, "cmd_dir");
-    // // });
-    // let t3 = thread::spawn(move || download(url, "download_dir"));
-    // // t1.join().expect("t1 异常");
-    // // t2.join().expect("t2 异常");
-    // t3.join().expect("t3 异常");
-    // let duration = start.elapsed();
-    // println!("\n\n总耗时: {:?}", duration);
+/// 开始同步前的准备: 安装 Ctrl-C 处理, 并把套接字超时设为各仓库停滞检测时长中
+/// 最长的一个, 不会早于任何仓库自己的检测触发. 有仓库关闭了检测时不设置
+fn prepare_sync(repos: &[Repo]) {
+    cancel::install_handler();
+    let stalls = repos.iter().map(|r| r.options.stall_timeout);
+    if stalls.clone().all(|s| s > 0) {
+        if let Some(secs) = stalls.max() {
+            cancel::set_socket_timeout(Duration::from_secs(secs));
+        }
+    }
+}
+
+/// 读入策略脚本并启动运行它的线程, 失败时与清单错误一样以 [`EXIT_CONFIG`] 退出
+fn load_policy(path: &Path, repos: &[Repo]) -> PolicyHost {
+    let script = match PolicyScript::load(path, repos) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("读取策略脚本 {} 失败: {}", path.display(), e);
+            process::exit(EXIT_CONFIG);
+        }
+    };
+    match PolicyHost::start(script) {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(EXIT_CONFIG);
+        }
+    }
+}
+
+fn load_manifest(path: &Path) -> Manifest {
+    match Manifest::load(path) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("读取清单 {} 失败: {}", path.display(), e);
+            process::exit(EXIT_CONFIG);
+        }
+    }
+}
+
+/// 打印清单中每个仓库的状态, 有仓库读取失败时返回 false
+fn status(manifest: &Manifest, format: OutputFormat) -> Result<bool, serde_json::Error> {
+    let statuses: Vec<RepoStatus> = manifest.repos.iter().map(RepoStatus::collect).collect();
+    match format {
+        OutputFormat::Table => status::print_table(&statuses),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&statuses)?),
+    }
+    Ok(statuses.iter().all(|s| s.error.is_none()))
+}
+
+fn serve(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("http service ready, addr: {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        thread::spawn(|| {
+            handle_connection(stream);
+        });
+    }
+    Ok(())
+}
+
+fn bench_hash(iterations: u32) {
     let start = Instant::now();
-    let mut s = DefaultHasher::new();
     let str = "hello word岁的法国看见帅哥受到了攻击防护谁有下次v白色乳液和, [] {}sdfg 世界各地饭后水果spigufhfsdvb _*R%#%#@$@?><~@";
     let mut r: u64 = 0;
-    for _i in 1..10000 {
-        // str.hash(&mut s);
-        // r = s.finish();
+    for _i in 0..iterations {
         r = const_xxh3(str.as_bytes());
     }
     println!("测试:{}", r);
     println!("耗时:{:#?}", start.elapsed())
 }
 
-fn download(url: &str, path: &str) {
-    let start = Instant::now();
-    let mut rio = RepositoryInitOptions::new();
-    rio.origin_url(url);
-    let repo = git2::Repository::init_opts(path, &mut rio).expect("初始化异常");
-    repo.remote_set_url("main", url)
-        .expect("TODO: panic message");
-    let mut cob = CheckoutBuilder::new();
-    cob.recreate_missing(true);
-    repo.checkout_head(Some(&mut cob)).expect("检出异常");
-
-    // let mut r = repo.remote(path, url).expect("远端库异常");
-    // println!("name:{:?}", r.name());
-    // println!("url:{:?}", r.url());
-    // let mut fo = FetchOptions::new();
-    // let mut rc = RemoteCallbacks::new();
-    // // rc.transfer_progress(|_| {
-    // //     println!("进度");
-    // //     true
-    // // });
-    // fo.remote_callbacks(rc);
-    // r.fetch(&["master"], Some(&mut fo), None)
-    //     .expect("fetch 出错");
-    let duration = start.elapsed();
-    println!("download 耗时: {:?}", duration);
-}
-
-fn clone(url: &str, path: &str) {
-    let start = Instant::now();
-    match git2::Repository::clone(url, path) {
-        Ok(repo) => {
-            println!("clone success");
-            repo
+fn run(cli: Cli) -> Result<bool, Box<dyn Error>> {
+    let progress = cli.progress.progress();
+    match cli.command {
+        Command::Sync {
+            manifest,
+            jobs,
+            changelog,
+            policy,
+        } => {
+            let manifest = load_manifest(&manifest);
+            prepare_sync(&manifest.repos);
+            let changelog = changelog.or_else(|| manifest.changelog.clone());
+            let policy = policy
+                .or_else(|| manifest.policy.clone())
+                .map(|p| load_policy(&p, &manifest.repos));
+            Ok(sync(
+                &manifest,
+                jobs,
+                changelog.as_deref(),
+                policy,
+                progress,
+            ))
         }
-        Err(e) => panic!("failed to clone: {}", e),
-    };
-    let duration = start.elapsed();
-    println!("clone 耗时: {:?}", duration);
+        Command::Daemon {
+            manifest,
+            jobs,
+            interval,
+            state,
+            listen,
+            changelog,
+            policy,
+        } => {
+            let manifest = load_manifest(&manifest);
+            prepare_sync(&manifest.repos);
+            let ctx = SyncContext {
+                creds: manifest.credentials(),
+                progress,
+                cache: manifest.cache.clone().map(ObjectCache::new),
+                policy: policy
+                    .or_else(|| manifest.policy.clone())
+                    .map(|p| load_policy(&p, &manifest.repos)),
+            };
+            let opts = daemon::Options {
+                concurrency: jobs
+                    .or(manifest.concurrency)
+                    .unwrap_or_else(pool::default_concurrency),
+                interval: interval.or(manifest.interval).unwrap_or(DEFAULT_INTERVAL),
+                state,
+                listen,
+                changelog: changelog.or_else(|| manifest.changelog.clone()),
+            };
+            daemon::run(&manifest.repos, &ctx, opts)?;
+            Ok(true)
+        }
+        Command::Status { manifest, format } => Ok(status(&load_manifest(&manifest), format)?),
+        Command::Clone {
+            url,
+            path,
+            branch,
+            strategy,
+            depth,
+            single_branch,
+            no_tags,
+            refspecs,
+        } => {
+            let start = Instant::now();
+            match strategy.clone_strategy() {
+                Some(clone) => {
+                    let repo = Repo {
+                        url,
+                        path,
+                        branch,
+                        options: RepoOptions {
+                            depth,
+                            single_branch,
+                            tags: !no_tags,
+                            clone,
+                            refspecs,
+                            ..Default::default()
+                        },
+                    };
+                    prepare_sync(std::slice::from_ref(&repo));
+                    let ctx = SyncContext {
+                        creds: Credentials::default(),
+                        progress,
+                        cache: None,
+                        policy: None,
+                    };
+                    repo.clone(&ctx)?
+                }
+                None => {
+                    Repository::clone(&url, &path)?;
+                }
+            }
+            println!("{:?} 耗时: {:?}", strategy, start.elapsed());
+            Ok(true)
+        }
+        Command::Pull {
+            path,
+            branch,
+            remote,
+            dirty,
+            merge,
+            rebase,
+        } => {
+            let url = Repository::open(&path)?
+                .find_remote(&remote)?
+                .url()
+                .unwrap_or_default()
+                .to_string();
+            let repo = Repo {
+                url,
+                path,
+                branch,
+                options: RepoOptions {
+                    remote,
+                    dirty,
+                    merge,
+                    pull: if rebase {
+                        PullMode::Rebase
+                    } else {
+                        PullMode::Merge
+                    },
+                    ..Default::default()
+                },
+            };
+            prepare_sync(std::slice::from_ref(&repo));
+            let ctx = SyncContext {
+                creds: Credentials::default(),
+                progress,
+                cache: None,
+                policy: None,
+            };
+            let report = repo.update(Path::new(&repo.path), &ctx)?;
+            print_report(&report);
+            Ok(true)
+        }
+        Command::Serve { addr } => {
+            serve(&addr)?;
+            Ok(true)
+        }
+        Command::Bench {
+            target: BenchTarget::Hash { iterations },
+        } => {
+            bench_hash(iterations);
+            Ok(true)
+        }
+        Command::Bench {
+            target:
+                BenchTarget::Clone {
+                    url,
+                    branch,
+                    runs,
+                    warmup,
+                    strategies,
+                    dir,
+                    format,
+                },
+        } => {
+            cancel::install_handler();
+            let opts = bench::Options {
+                url,
+                branch,
+                runs,
+                warmup,
+                strategies,
+                dir,
+            };
+            let summaries = bench::run(&opts)?;
+            bench::print(&summaries, &opts, format)?;
+            Ok(true)
+        }
+    }
 }
 
-fn cmd(url: &str, path: &str) {
-    let start = Instant::now();
-    let _output = process::Command::new("git") // 指定要运行的命令为 "cmd"
-        .arg("clone") // 添加参数 "/C" 表示执行完后关闭 CMD 窗口
-        .arg(url)
-        .arg(path)
-        .output() // 获取输出结果
-        .expect("cmd clone 异常"); // 如果发生错误则 panic
+fn main() {
+    // call_dll();
 
-    // println!(
-    //     "cmd clone 结果:\n{}",
-    //     String::from_utf8(output.stdout).unwrap()
-    // ); // 打印标准输出内容
-    let duration = start.elapsed();
-    println!("cmd clone 耗时: {:?}", duration);
+    let result = run(Cli::parse());
+    if cancel::requested() {
+        process::exit(EXIT_CANCELLED);
+    }
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(EXIT_FAILURE),
+        Err(e) => {
+            eprintln!("错误: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
 }
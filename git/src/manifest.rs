@@ -0,0 +1,95 @@
+use crate::credentials::{Credentials, HostCredential};
+use crate::repo::Repo;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 仓库清单, 支持 TOML 和 JSON 两种格式
+///
+/// ```toml
+/// [[repos]]
+/// url = "https://gitee.com/caretop/caretop7_next.git"
+/// path = "repo_2"
+/// branch = "master"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub repos: Vec<Repo>,
+    /// 同时同步的仓库数上限
+    pub concurrency: Option<usize>,
+    /// 把每次同步的变更日志 (JSON Lines) 追加到该文件
+    pub changelog: Option<PathBuf>,
+    /// daemon 模式下各仓库默认的同步间隔 (秒)
+    pub interval: Option<u64>,
+    /// 共享对象缓存的目录, 开启 `shared` 的仓库按地址引用其中的裸仓库
+    pub cache: Option<PathBuf>,
+    /// JavaScript 策略脚本, 见 [`PolicyScript`](crate::policy::PolicyScript)
+    pub policy: Option<PathBuf>,
+    /// 按主机配置的凭据, 见 [`HostCredential`]
+    #[serde(default)]
+    pub credentials: Vec<HostCredential>,
+}
+
+impl Manifest {
+    /// 按扩展名选择解析格式, `.json` 以外一律当作 TOML
+    pub fn load(path: &Path) -> io::Result<Manifest> {
+        let text = fs::read_to_string(path)?;
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json {
+            Manifest::from_json(&text)
+        } else {
+            Manifest::from_toml(&text)
+        }
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        Credentials::new(&self.credentials)
+    }
+
+    pub fn from_toml(text: &str) -> io::Result<Manifest> {
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_json(text: &str) -> io::Result<Manifest> {
+        serde_json::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_manifest_parses() {
+        let text = include_str!("../repos.example.toml");
+        assert!(!Manifest::from_toml(text).unwrap().repos.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let repo = "[[repos]]\nurl = \"u\"\npath = \"p\"\nbranch = \"master\"\n";
+        let cases = [
+            "concurency = 4\n".to_string(),
+            format!("{}brnach = \"dev\"\n", repo),
+            format!("{}[repos.options]\ndepht = 1\n", repo),
+            format!("{}[repos.options.retry]\nattempt = 3\n", repo),
+            "[[credentials]]\nkind = \"env\"\nusername_env = \"U\"\npassword_env = \"P\"\npasword_env = \"P\"\n".to_string(),
+        ];
+        for text in cases {
+            assert!(Manifest::from_toml(&text).is_err(), "{}", text);
+        }
+        let json = r#"{"repos": [{"url": "u", "path": "p", "branch": "master", "option": {}}]}"#;
+        assert!(Manifest::from_json(json).is_err());
+    }
+
+    #[test]
+    fn credentials_host_is_known() {
+        let text = "[[credentials]]\nhost = \"gitee.com\"\nkind = \"ssh-agent\"\n";
+        assert_eq!(Manifest::from_toml(text).unwrap().credentials.len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::error::SyncError;
+use crate::progress::{Phase, Reporter};
+use git2::{ErrorCode, FileFavor, Index, MergeOptions, Oid, RebaseOptions, Repository, Signature};
+use serde::Deserialize;
+use std::fmt;
+
+/// 合并出现冲突时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// 放弃合并, HEAD 和工作区保持不变, 返回冲突报告
+    Abort,
+    /// 冲突内容以本地为准
+    Ours,
+    /// 冲突内容以远端为准
+    Theirs,
+    /// 把冲突写入索引和工作区 (带 MERGE_HEAD), 留给人工处理
+    Leave,
+}
+
+/// 拉取时整合远端提交的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PullMode {
+    /// 快进或生成合并提交
+    Merge,
+    /// 把本地提交变基到 FETCH_HEAD 之上
+    Rebase,
+}
+
+/// 一个冲突文件, 三方对应的 blob id, 某一方不存在 (新增/删除) 时为 `None`
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub ancestor: Option<Oid>,
+    pub ours: Option<Oid>,
+    pub theirs: Option<Oid>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let short = |id: &Option<Oid>| match id {
+            Some(id) => id.to_string()[..8].to_string(),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{} [base {} ours {} theirs {}]",
+            self.path,
+            short(&self.ancestor),
+            short(&self.ours),
+            short(&self.theirs)
+        )
+    }
+}
+
+/// 合并的结果
+#[derive(Debug, Clone)]
+pub enum MergeOutcome {
+    UpToDate,
+    FastForward {
+        from: Option<Oid>,
+        to: Oid,
+    },
+    /// 生成了合并提交, `resolved` 是按 ours/theirs 策略自动解决的冲突
+    Merged {
+        from: Oid,
+        to: Oid,
+        resolved: Vec<Conflict>,
+    },
+    /// 变基完成, `replayed` 是重放的本地提交数 (已包含在上游中的会被跳过)
+    Rebased {
+        from: Oid,
+        to: Oid,
+        replayed: usize,
+    },
+}
+
+pub fn conflicts(idx: &Index) -> Result<Vec<Conflict>, git2::Error> {
+    let mut list = vec![];
+    for conflict in idx.conflicts()? {
+        let conflict = conflict?;
+        let path = [&conflict.our, &conflict.their, &conflict.ancestor]
+            .into_iter()
+            .flatten()
+            .next()
+            .map(|e| String::from_utf8_lossy(&e.path).to_string())
+            .unwrap_or_default();
+        list.push(Conflict {
+            path,
+            ancestor: conflict.ancestor.map(|e| e.id),
+            ours: conflict.our.map(|e| e.id),
+            theirs: conflict.their.map(|e| e.id),
+        });
+    }
+    Ok(list)
+}
+
+/// 不改动仓库, 预先计算把 `theirs` 合并到 `ours` 会冲突的文件
+pub fn preview_conflicts(
+    repo: &Repository,
+    ours: Oid,
+    theirs: Oid,
+) -> Result<Vec<Conflict>, git2::Error> {
+    let idx = repo.merge_commits(&repo.find_commit(ours)?, &repo.find_commit(theirs)?, None)?;
+    conflicts(&idx)
+}
+
+/// 仓库未配置 user.name/user.email 时使用默认签名
+pub fn signature(repo: &Repository) -> Result<Signature<'static>, git2::Error> {
+    repo.signature()
+        .or_else(|_| Signature::now("rust-demo", "rust-demo@localhost"))
+}
+
+fn fast_forward(
+    repo: &Repository,
+    lb: &mut git2::Reference,
+    rc: &git2::AnnotatedCommit,
+    reporter: Reporter,
+) -> Result<(), git2::Error> {
+    let name = match lb.name() {
+        Some(s) => s.to_string(),
+        None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
+    };
+    let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
+    reporter.message(&msg);
+    // 先以 safe 模式检出目标提交再移动分支, 工作区有冲突的本地修改时报错而不是覆盖
+    let target = repo.find_object(rc.id(), None)?;
+    reporter.phase(Phase::Checkout);
+    repo.checkout_tree(&target, Some(reporter.checkout_builder().safe()))?;
+    lb.set_target(rc.id(), &msg)?;
+    repo.set_head(&name)?;
+    Ok(())
+}
+
+fn normal_merge(
+    repo: &Repository,
+    local: &git2::AnnotatedCommit,
+    remote: &git2::AnnotatedCommit,
+    strategy: MergeStrategy,
+    reporter: Reporter,
+) -> Result<Vec<Conflict>, SyncError> {
+    let local_tree = repo.find_commit(local.id())?.tree()?;
+    let remote_tree = repo.find_commit(remote.id())?.tree()?;
+    let ancestor = repo
+        .find_commit(repo.merge_base(local.id(), remote.id())?)?
+        .tree()?;
+    let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
+
+    let mut resolved = vec![];
+    if idx.has_conflicts() {
+        let found = conflicts(&idx)?;
+        reporter.message(&format!(
+            "Merge conflicts detected ({} files), strategy: {:?}",
+            found.len(),
+            strategy
+        ));
+        let favor = match strategy {
+            MergeStrategy::Abort => return Err(SyncError::MergeConflict { conflicts: found }),
+            MergeStrategy::Leave => {
+                // 交给 libgit2 写入冲突索引, 工作区冲突标记和 MERGE_HEAD
+                let mut checkout = reporter.checkout_builder();
+                checkout
+                    .safe()
+                    .allow_conflicts(true)
+                    .conflict_style_merge(true);
+                repo.merge(&[remote], None, Some(&mut checkout))?;
+                return Err(SyncError::MergeConflict { conflicts: found });
+            }
+            MergeStrategy::Ours => FileFavor::Ours,
+            MergeStrategy::Theirs => FileFavor::Theirs,
+        };
+        let mut opts = MergeOptions::new();
+        opts.file_favor(favor);
+        idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, Some(&opts))?;
+        // 增删类冲突无法按内容取舍, 仍然放弃合并
+        if idx.has_conflicts() {
+            return Err(SyncError::MergeConflict {
+                conflicts: conflicts(&idx)?,
+            });
+        }
+        resolved = found;
+    }
+    let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
+    // 先检出合并结果再提交, 工作区与 HEAD 不一致时 safe 检出会报错而不是覆盖
+    reporter.phase(Phase::Checkout);
+    repo.checkout_tree(
+        result_tree.as_object(),
+        Some(reporter.checkout_builder().safe()),
+    )?;
+    // now create the merge commit
+    let msg = format!("Merge: {} into {}", remote.id(), local.id());
+    let sig = signature(repo)?;
+    let local_commit = repo.find_commit(local.id())?;
+    let remote_commit = repo.find_commit(remote.id())?;
+    // Do our merge commit and set current branch head to that commit.
+    repo.commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        &msg,
+        &result_tree,
+        &[&local_commit, &remote_commit],
+    )?;
+    Ok(resolved)
+}
+
+/// 把当前分支上的本地提交逐个重放到 `fetch_commit` 之上.
+///
+/// 在内存中重放, 全部成功后才检出结果并移动分支; 出现冲突或任何错误都会 abort,
+/// 分支和工作区保持不变 (稀疏检出的工作区无法通过 libgit2 变基前的干净检查). 变基时 libgit2 的
+/// "ours" 是上游, "theirs" 是正在重放的本地提交, 所以 ours/theirs 策略要对调;
+/// leave 在变基中没有意义, 按 abort 处理.
+pub fn do_rebase(
+    repo: &Repository,
+    remote_branch: &str,
+    fetch_commit: git2::AnnotatedCommit,
+    strategy: MergeStrategy,
+    reporter: Reporter,
+) -> Result<MergeOutcome, SyncError> {
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+    if !analysis.0.is_normal() {
+        // 已是最新或可以快进, 与合并模式相同
+        return do_merge(repo, remote_branch, fetch_commit, strategy, reporter);
+    }
+
+    let head = repo.reference_to_annotated_commit(&repo.head()?)?;
+    let mut opts = RebaseOptions::new();
+    let mut merge_opts = MergeOptions::new();
+    match strategy {
+        MergeStrategy::Ours => {
+            merge_opts.file_favor(FileFavor::Theirs);
+        }
+        MergeStrategy::Theirs => {
+            merge_opts.file_favor(FileFavor::Ours);
+        }
+        MergeStrategy::Abort | MergeStrategy::Leave => {}
+    }
+    opts.inmemory(true).merge_options(merge_opts);
+    reporter.message(&format!(
+        "Rebasing {} onto {}",
+        head.id(),
+        fetch_commit.id()
+    ));
+    let mut rebase = repo.rebase(Some(&head), Some(&fetch_commit), None, Some(&mut opts))?;
+    let sig = signature(repo)?;
+
+    let mut replayed = 0;
+    let mut last = fetch_commit.id();
+    let result = (|| -> Result<(), SyncError> {
+        while let Some(op) = rebase.next() {
+            op?;
+            let idx = rebase.inmemory_index()?;
+            if idx.has_conflicts() {
+                // 报告里的 ours/theirs 统一指本地/远端
+                let conflicts = conflicts(&idx)?
+                    .into_iter()
+                    .map(|c| Conflict {
+                        ours: c.theirs,
+                        theirs: c.ours,
+                        ..c
+                    })
+                    .collect();
+                return Err(SyncError::MergeConflict { conflicts });
+            }
+            match rebase.commit(None, &sig, None) {
+                Ok(id) => {
+                    replayed += 1;
+                    last = id;
+                }
+                // 该提交的改动上游已经有了, 跳过
+                Err(e) if e.code() == ErrorCode::Applied => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        reporter.message(&format!("Rebase failed, aborting: {}", e));
+        rebase.abort()?;
+        return Err(e);
+    }
+    rebase.finish(Some(&sig))?;
+
+    // 与快进相同, 先以 safe 模式检出变基结果再移动分支
+    let target = repo.find_object(last, None)?;
+    reporter.phase(Phase::Checkout);
+    repo.checkout_tree(&target, Some(reporter.checkout_builder().safe()))?;
+    let msg = format!("rebase: {} onto {}", head.id(), fetch_commit.id());
+    repo.head()?.set_target(last, &msg)?;
+
+    Ok(MergeOutcome::Rebased {
+        from: head.id(),
+        to: last,
+        replayed,
+    })
+}
+
+pub fn do_merge<'a>(
+    repo: &'a Repository,
+    remote_branch: &str,
+    fetch_commit: git2::AnnotatedCommit<'a>,
+    strategy: MergeStrategy,
+    reporter: Reporter,
+) -> Result<MergeOutcome, SyncError> {
+    // 1. do a merge analysis
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+    let head = repo.head().ok().and_then(|h| h.target());
+
+    // 2. Do the appropriate merge
+    if analysis.0.is_fast_forward() {
+        reporter.message("Doing a fast forward");
+        // do a fast forward
+        let refname = format!("refs/heads/{}", remote_branch);
+        match repo.find_reference(&refname) {
+            Ok(mut r) => {
+                fast_forward(repo, &mut r, &fetch_commit, reporter)?;
+            }
+            Err(_) => {
+                // The branch doesn't exist so just set the reference to the
+                // commit directly. Usually this is because you are pulling
+                // into an empty repository.
+                repo.reference(
+                    &refname,
+                    fetch_commit.id(),
+                    true,
+                    &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
+                )?;
+                repo.set_head(&refname)?;
+                reporter.phase(Phase::Checkout);
+                repo.checkout_head(Some(
+                    reporter
+                        .checkout_builder()
+                        .allow_conflicts(true)
+                        .conflict_style_merge(true)
+                        .force(),
+                ))?;
+            }
+        };
+        Ok(MergeOutcome::FastForward {
+            from: head,
+            to: fetch_commit.id(),
+        })
+    } else if analysis.0.is_normal() {
+        // do a normal merge
+        let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
+        let resolved = normal_merge(repo, &head_commit, &fetch_commit, strategy, reporter)?;
+        Ok(MergeOutcome::Merged {
+            from: head_commit.id(),
+            to: repo.head()?.peel_to_commit()?.id(),
+            resolved,
+        })
+    } else {
+        reporter.message("Nothing to do...");
+        Ok(MergeOutcome::UpToDate)
+    }
+}
@@ -0,0 +1,181 @@
+use crate::error::SyncError;
+use git2::{Error, ErrorClass, ErrorCode, Oid, Repository};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+/// 把仓库固定在某个版本上, 同步时解析出提交并以分离 HEAD 检出, 不再跟随 `branch`
+///
+/// ```toml
+/// [repos.options]
+/// pin = { tag = "v1.2.0" }
+/// # pin = { commit = "3f2a9c1" }
+/// # pin = { semver = "v1.*" }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pin {
+    Tag(String),
+    /// 完整或缩写的提交 id
+    Commit(String),
+    /// 标签前缀加版本要求, 例如 `v1.*`, `release-^2.3`, 取满足要求的最高版本.
+    /// 前缀必须以 `-` 或 `v` 结尾, 可以带数字 (`go1-v1.*`)
+    Semver(String),
+}
+
+/// 固定版本的检出结果
+#[derive(Debug, Clone)]
+pub struct PinOutcome {
+    /// 解析到的标签名或提交 id
+    pub target: String,
+    pub from: Option<Oid>,
+    pub to: Oid,
+}
+
+impl PinOutcome {
+    /// 本次同步后检出的提交是否发生变化
+    pub fn moved(&self) -> bool {
+        self.from != Some(self.to)
+    }
+}
+
+impl Pin {
+    /// 需要拉取的 refspec, 固定到提交时本地已有该提交则无需拉取
+    pub fn refspecs(&self, repo: &Repository) -> Option<Vec<&'static str>> {
+        match self {
+            Pin::Tag(_) | Pin::Semver(_) => Some(vec!["+refs/tags/*:refs/tags/*"]),
+            Pin::Commit(id) => match repo.revparse_single(id).and_then(|o| o.peel_to_commit()) {
+                Ok(_) => None,
+                // 使用远端配置的 refspec 拉取所有分支
+                Err(_) => Some(vec![]),
+            },
+        }
+    }
+
+    /// 在本地仓库中解析出目标提交, 返回 (标签名或提交 id, 提交 id)
+    pub fn resolve(&self, repo: &Repository) -> Result<(String, Oid), SyncError> {
+        let (name, spec) = match self {
+            Pin::Tag(tag) => (tag.clone(), format!("refs/tags/{}", tag)),
+            Pin::Commit(id) => (id.clone(), id.clone()),
+            Pin::Semver(pattern) => {
+                let tag = latest_tag(repo, pattern)?;
+                (tag.clone(), format!("refs/tags/{}", tag))
+            }
+        };
+        let commit = repo.revparse_single(&spec)?.peel_to_commit()?;
+        Ok((name, commit.id()))
+    }
+}
+
+/// 把 `v1.*` 拆成标签前缀 `v` 和版本要求 `1.*`.
+///
+/// 前缀以 `-` 或 `v` 结尾, 本身可以带数字 (`go1-v1.*`). 从最后一个 `-`/`v` 往前找,
+/// 取第一个使剩余部分是合法版本要求的位置, 这样预发布版本中的 `-` 不会被当作前缀;
+/// 都不合法时按最后一个位置拆开, 由调用方报告错误
+fn split_pattern(pattern: &str) -> (&str, &str) {
+    let bounds: Vec<usize> = std::iter::once(0)
+        .chain(pattern.match_indices(['-', 'v']).map(|(i, _)| i + 1))
+        .collect();
+    let at = bounds
+        .iter()
+        .rev()
+        .find(|&&at| VersionReq::parse(&pattern[at..]).is_ok())
+        .or(bounds.last())
+        .copied()
+        .unwrap_or(0);
+    pattern.split_at(at)
+}
+
+fn latest_tag(repo: &Repository, pattern: &str) -> Result<String, SyncError> {
+    let (prefix, req) = split_pattern(pattern);
+    let req = VersionReq::parse(req)
+        .map_err(|e| Error::from_str(&format!("无效的版本要求 {}: {}", pattern, e)))?;
+    let tags = repo.tag_names(None)?;
+    tags.iter()
+        .flatten()
+        .filter_map(|tag| {
+            let version = Version::parse(tag.strip_prefix(prefix)?).ok()?;
+            req.matches(&version).then_some((version, tag))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, tag)| tag.to_string())
+        .ok_or_else(|| {
+            let msg = format!("没有与 {} 匹配的标签", pattern);
+            Error::new(ErrorCode::NotFound, ErrorClass::Reference, msg).into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    #[test]
+    fn split_prefixed_patterns() {
+        assert_eq!(split_pattern("v1.*"), ("v", "1.*"));
+        assert_eq!(split_pattern("release-^2.3"), ("release-", "^2.3"));
+        assert_eq!(split_pattern(">=1.2, <2"), ("", ">=1.2, <2"));
+        assert_eq!(split_pattern("v"), ("v", ""));
+        // 前缀中的数字和版本要求中的预发布标记
+        assert_eq!(split_pattern("go1-v1.*"), ("go1-v", "1.*"));
+        assert_eq!(split_pattern("release2-^1.2"), ("release2-", "^1.2"));
+        assert_eq!(split_pattern("v>=1.0.0-rc.1"), ("v", ">=1.0.0-rc.1"));
+    }
+
+    /// 在一个提交上打出 `tags` 的临时仓库
+    fn tagged(name: &str, tags: &[&str]) -> (PathBuf, Repository) {
+        let dir = std::env::temp_dir().join(format!("rust-demo-pin-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        {
+            let tree = repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = repo.find_tree(tree).unwrap();
+            let sig = git2::Signature::now("t", "t@t").unwrap();
+            let id = repo
+                .commit(Some("HEAD"), &sig, &sig, "c", &tree, &[])
+                .unwrap();
+            let commit = repo.find_object(id, None).unwrap();
+            for tag in tags {
+                repo.tag_lightweight(tag, &commit, false).unwrap();
+            }
+        }
+        (dir, repo)
+    }
+
+    #[test]
+    fn latest_matching_tag() {
+        let (dir, repo) = tagged(
+            "latest",
+            &[
+                "v1.0.0",
+                "v1.2.0",
+                "v1.10.0-rc.1",
+                "v2.0.0",
+                "release-1.5.0",
+                "1.9.0",
+                "go1-v1.3.0",
+            ],
+        );
+        // 按版本而不是字符串比较, 预发布版本不满足普通的版本要求
+        assert_eq!(latest_tag(&repo, "v1.*").unwrap(), "v1.2.0");
+        assert_eq!(latest_tag(&repo, "v*").unwrap(), "v2.0.0");
+        assert_eq!(latest_tag(&repo, "release-^1").unwrap(), "release-1.5.0");
+        assert_eq!(latest_tag(&repo, "1.*").unwrap(), "1.9.0");
+        assert_eq!(latest_tag(&repo, "go1-v1.*").unwrap(), "go1-v1.3.0");
+        assert_eq!(
+            latest_tag(&repo, "v>=1.10.0-rc.0, <2").unwrap(),
+            "v1.10.0-rc.1"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn no_matching_tag() {
+        let (dir, repo) = tagged("none", &["v1.0.0", "release-3.0.0"]);
+        let e = latest_tag(&repo, "v3.*").unwrap_err();
+        assert_eq!(e.kind(), "not-found");
+        assert!(latest_tag(&repo, "v1.x.y").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
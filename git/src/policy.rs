@@ -0,0 +1,346 @@
+use crate::changelog::Changelog;
+use crate::error::SyncError;
+use crate::merge::{Conflict, MergeStrategy};
+use crate::repo::Repo;
+use crate::status::{HeadCommit, RepoStatus};
+use deno_core::error::{type_error, AnyError};
+use deno_core::{op2, serde_v8, v8, FastString, JsRuntime, OpState, RuntimeOptions};
+use git2::{Repository, Sort};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// 单次调用策略函数的时长上限, 超过后终止脚本执行
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// `git.log` 最多返回的提交数
+const MAX_LOG: usize = 1000;
+
+/// 在用户脚本之前执行, 把 op 包装成 `git` 和 `console`
+const PRELUDE: &str = r#"(() => {
+  const ops = Deno.core.ops;
+  const text = (v) => (typeof v === "string" ? v : JSON.stringify(v));
+  globalThis.console = {
+    log: (...args) => ops.op_policy_log(args.map(text).join(" ")),
+  };
+  globalThis.git = Object.freeze({
+    status: (path) => ops.op_repo_status(path),
+    log: (path, rev = "HEAD", limit = 20) => ops.op_repo_log(path, rev, limit),
+  });
+})();"#;
+
+/// 策略可以查询的仓库, 即清单中的仓库
+struct Listed(Vec<Repo>);
+
+fn listed<'a>(state: &'a OpState, path: &str) -> Result<&'a Repo, AnyError> {
+    state
+        .borrow::<Listed>()
+        .0
+        .iter()
+        .find(|r| r.path == path)
+        .ok_or_else(|| type_error(format!("{} 不是清单中的仓库", path)))
+}
+
+#[op2]
+#[serde]
+fn op_repo_status(state: &OpState, #[string] path: String) -> Result<RepoStatus, AnyError> {
+    Ok(RepoStatus::collect(listed(state, &path)?))
+}
+
+/// 从 `rev` 开始按时间倒序的最多 `limit` 个提交
+#[op2]
+#[serde]
+fn op_repo_log(
+    state: &OpState,
+    #[string] path: String,
+    #[string] rev: String,
+    #[smi] limit: u32,
+) -> Result<Vec<HeadCommit>, AnyError> {
+    let repo = Repository::open(&listed(state, &path)?.path)?;
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TIME)?;
+    walk.push(repo.revparse_single(&rev)?.peel_to_commit()?.id())?;
+    let mut commits = vec![];
+    for id in walk.take((limit as usize).min(MAX_LOG)) {
+        let commit = repo.find_commit(id?)?;
+        commits.push(HeadCommit {
+            id: commit.id().to_string(),
+            author: commit.author().name().unwrap_or_default().to_string(),
+            time: commit.time().seconds(),
+            summary: commit.summary().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(commits)
+}
+
+#[op2]
+fn op_policy_log(#[string] message: String) {
+    println!("[policy] {}", message);
+}
+
+deno_core::extension!(
+    rust_demo_policy,
+    ops = [op_repo_status, op_repo_log, op_policy_log],
+    options = { repos: Vec<Repo> },
+    state = |state, options| {
+        state.put(Listed(options.repos));
+    },
+);
+
+/// 用 JavaScript 编写的同步策略, 由内嵌的 `JsRuntime` 执行. 脚本在全局定义以下同步函数,
+/// 缺少的按默认行为处理 (示例见 policy.example.js):
+///
+/// - `shouldSync(repo)`: 是否同步该仓库, 默认 true
+/// - `acceptCommits(repo, changelog)`: 拉取到新提交时调用, 返回 false 或字符串 (原因) 时拒绝
+/// - `onConflict(repo, conflicts)`: 合并会冲突时返回 abort/ours/theirs/leave, 覆盖清单中的 merge
+///
+/// 脚本只能通过 `git.status(path)` 和 `git.log(path, rev, limit)` 只读地查询清单中的仓库,
+/// 运行时没有文件系统和网络相关的 op. 一次同步只用 [`PolicyHost::start`] 创建一个运行时
+pub struct PolicyScript {
+    pub path: PathBuf,
+    source: String,
+    repos: Vec<Repo>,
+}
+
+impl PolicyScript {
+    /// 读入 `path`, `repos` 是脚本可以查询的仓库
+    pub fn load(path: &Path, repos: &[Repo]) -> io::Result<PolicyScript> {
+        Ok(PolicyScript {
+            path: path.to_path_buf(),
+            source: fs::read_to_string(path)?,
+            repos: repos.to_vec(),
+        })
+    }
+
+    /// 创建运行时并执行脚本, 脚本有语法错误或顶层代码抛出异常时返回错误
+    fn instantiate(&self) -> Result<Policy, SyncError> {
+        let runtime = JsRuntime::new(RuntimeOptions {
+            extensions: vec![rust_demo_policy::init_ops(self.repos.clone())],
+            ..Default::default()
+        });
+        let mut policy = Policy { runtime };
+        policy.eval("prelude.js", PRELUDE.to_string())?;
+        policy
+            .eval("policy.js", self.source.clone())
+            .map_err(|e| SyncError::Policy(format!("{}: {}", self.path.display(), e)))?;
+        Ok(policy)
+    }
+}
+
+/// 发给策略线程执行的调用
+type Call = Box<dyn FnOnce(&mut Policy) + Send>;
+
+/// 在专用线程上运行的策略. `JsRuntime` 不能跨线程使用, 同步的各个工作线程把调用发给
+/// 持有运行时的线程依次执行, 整次同步共用一个运行时
+pub struct PolicyHost {
+    calls: Option<Sender<Call>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PolicyHost {
+    /// 启动策略线程并执行脚本, 脚本出错时返回 [`PolicyScript::instantiate`] 的错误
+    pub fn start(script: PolicyScript) -> Result<PolicyHost, SyncError> {
+        let (calls, received) = mpsc::channel::<Call>();
+        let (ready, started) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut policy = match script.instantiate() {
+                Ok(policy) => policy,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for call in received {
+                call(&mut policy);
+            }
+        });
+        let host = PolicyHost {
+            calls: Some(calls),
+            thread: Some(thread),
+        };
+        started.recv().unwrap_or_else(|_| Err(exited()))?;
+        Ok(host)
+    }
+
+    /// 在策略线程上执行 `f` 并等待结果
+    fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Policy) -> Result<R, SyncError> + Send + 'static,
+    ) -> Result<R, SyncError> {
+        let (done, result) = mpsc::channel();
+        let call: Call = Box::new(move |policy| {
+            let _ = done.send(f(policy));
+        });
+        let calls = self.calls.as_ref().ok_or_else(exited)?;
+        calls.send(call).map_err(|_| exited())?;
+        result.recv().unwrap_or_else(|_| Err(exited()))
+    }
+
+    pub fn should_sync(&self, repo: &Repo) -> Result<bool, SyncError> {
+        let repo = repo.to_owned();
+        self.call(move |policy| policy.should_sync(&repo))
+    }
+
+    pub fn accept_commits(
+        &self,
+        repo: &Repo,
+        log: &Changelog,
+    ) -> Result<Option<String>, SyncError> {
+        let (repo, log) = (repo.to_owned(), log.clone());
+        self.call(move |policy| policy.accept_commits(&repo, &log))
+    }
+
+    pub fn on_conflict(
+        &self,
+        repo: &Repo,
+        conflicts: &[Conflict],
+    ) -> Result<Option<MergeStrategy>, SyncError> {
+        let (repo, conflicts) = (repo.to_owned(), conflicts.to_vec());
+        self.call(move |policy| policy.on_conflict(&repo, &conflicts))
+    }
+}
+
+impl Drop for PolicyHost {
+    fn drop(&mut self) {
+        // 先关闭通道, 策略线程处理完已发出的调用后退出
+        self.calls.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn exited() -> SyncError {
+    SyncError::Policy("策略线程已退出".to_string())
+}
+
+/// 一个已加载策略脚本的 JS 运行时
+pub struct Policy {
+    runtime: JsRuntime,
+}
+
+impl Policy {
+    /// 执行一段脚本并把结果转换为 JSON. 执行超过 [`CALL_TIMEOUT`] 时从另一个线程终止它,
+    /// 避免脚本中的死循环卡住同步
+    fn eval(&mut self, name: &'static str, code: String) -> Result<Value, SyncError> {
+        let handle = self.runtime.v8_isolate().thread_safe_handle();
+        let (done, wait) = mpsc::channel::<()>();
+        let timer = thread::spawn(move || {
+            let expired = wait.recv_timeout(CALL_TIMEOUT) == Err(RecvTimeoutError::Timeout);
+            if expired {
+                handle.terminate_execution();
+            }
+            expired
+        });
+        let result = self.runtime.execute_script(name, FastString::from(code));
+        let _ = done.send(());
+        if timer.join().unwrap_or(false) {
+            self.runtime.v8_isolate().cancel_terminate_execution();
+            return Err(SyncError::Policy(format!(
+                "{} 执行超过 {:?}",
+                name, CALL_TIMEOUT
+            )));
+        }
+        let global = result.map_err(|e| SyncError::Policy(format!("{:#}", e)))?;
+        let scope = &mut self.runtime.handle_scope();
+        let value = v8::Local::new(scope, global);
+        if value.is_promise() {
+            return Err(SyncError::Policy(format!(
+                "{} 返回了 Promise, 策略函数必须是同步的",
+                name
+            )));
+        }
+        serde_v8::from_v8(scope, value).map_err(|e| SyncError::Policy(e.to_string()))
+    }
+
+    /// 调用全局函数 `name`, 没有定义该函数时返回 `None`
+    fn call(&mut self, name: &str, args: &[Value]) -> Result<Option<Value>, SyncError> {
+        // JSON 是 JS 表达式的子集, 参数直接写进调用代码
+        let code = format!(
+            "(() => {{ const f = globalThis[{}]; return typeof f === 'function' ? [f(...{})] : null; }})()",
+            json!(name),
+            Value::from(args)
+        );
+        let result = self.eval("call.js", code).map_err(|e| match e {
+            SyncError::Policy(message) => SyncError::Policy(format!("{}: {}", name, message)),
+            e => e,
+        })?;
+        Ok(match result {
+            Value::Array(mut values) => Some(values.pop().unwrap_or(Value::Null)),
+            _ => None,
+        })
+    }
+
+    /// `shouldSync(repo)`, 默认同步
+    pub fn should_sync(&mut self, repo: &Repo) -> Result<bool, SyncError> {
+        match self.call("shouldSync", &[repo_json(repo)])? {
+            None | Some(Value::Null) => Ok(true),
+            Some(Value::Bool(b)) => Ok(b),
+            Some(v) => Err(SyncError::Policy(format!(
+                "shouldSync 应返回布尔值, 实际为 {}",
+                v
+            ))),
+        }
+    }
+
+    /// `acceptCommits(repo, changelog)`, 拒绝时返回原因
+    pub fn accept_commits(
+        &mut self,
+        repo: &Repo,
+        log: &Changelog,
+    ) -> Result<Option<String>, SyncError> {
+        let log = serde_json::to_value(log).map_err(|e| SyncError::Policy(e.to_string()))?;
+        match self.call("acceptCommits", &[repo_json(repo), log])? {
+            None | Some(Value::Null) | Some(Value::Bool(true)) => Ok(None),
+            Some(Value::Bool(false)) => Ok(Some("acceptCommits 返回 false".to_string())),
+            Some(Value::String(reason)) => Ok(Some(reason)),
+            Some(v) => Err(SyncError::Policy(format!(
+                "acceptCommits 应返回布尔值或字符串, 实际为 {}",
+                v
+            ))),
+        }
+    }
+
+    /// `onConflict(repo, conflicts)`, 返回 `None` 时沿用清单中的 merge
+    pub fn on_conflict(
+        &mut self,
+        repo: &Repo,
+        conflicts: &[Conflict],
+    ) -> Result<Option<MergeStrategy>, SyncError> {
+        let id = |id: &Option<git2::Oid>| id.map(|id| id.to_string());
+        let conflicts: Vec<Value> = conflicts
+            .iter()
+            .map(|c| {
+                json!({
+                    "path": c.path,
+                    "ancestor": id(&c.ancestor),
+                    "ours": id(&c.ours),
+                    "theirs": id(&c.theirs),
+                })
+            })
+            .collect();
+        match self.call("onConflict", &[repo_json(repo), Value::from(conflicts)])? {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => serde_json::from_value(v.clone()).map(Some).map_err(|_| {
+                SyncError::Policy(format!(
+                    "onConflict 应返回 abort/ours/theirs/leave, 实际为 {}",
+                    v
+                ))
+            }),
+        }
+    }
+}
+
+/// 传给策略函数的仓库信息
+fn repo_json(repo: &Repo) -> Value {
+    json!({
+        "url": repo.url,
+        "path": repo.path,
+        "branch": repo.branch,
+        "mirror": repo.options.mirror,
+    })
+}
@@ -0,0 +1,76 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 单个任务的执行结果, 任务 panic 时 `result` 为 `Err(panic 信息)`
+pub struct Finished<R> {
+    pub duration: Duration,
+    pub result: Result<R, String>,
+}
+
+/// 最多用 `concurrency` 个线程并行处理 `items`, 返回结果与输入顺序一致.
+///
+/// 每个任务都包在 `catch_unwind` 中, 一个任务 panic 不影响其它任务;
+/// `on_finish` 在任务完成时由工作线程调用, 可用于实时输出进度.
+pub fn run<T, R, F, C>(items: &[T], concurrency: usize, f: F, on_finish: C) -> Vec<Finished<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    C: Fn(&T, &Finished<R>) + Sync,
+{
+    let workers = concurrency.clamp(1, items.len().max(1));
+    let next = AtomicUsize::new(0);
+    let slots: Vec<Mutex<Option<Finished<R>>>> = items.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let finished = measure(|| f(item));
+                on_finish(item, &finished);
+                *slots[i].lock().unwrap_or_else(|e| e.into_inner()) = Some(finished);
+            });
+        }
+    });
+
+    slots
+        .into_iter()
+        .map(|slot| {
+            slot.into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+                .expect("every item is processed before the scope ends")
+        })
+        .collect()
+}
+
+/// 执行 `f` 并计时, panic 被捕获为 `Err`
+pub fn measure<R>(f: impl FnOnce() -> R) -> Finished<R> {
+    let start = Instant::now();
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|e| panic_message(e.as_ref()));
+    Finished {
+        duration: start.elapsed(),
+        result,
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        format!("panic: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("panic: {}", s)
+    } else {
+        "panic".to_string()
+    }
+}
+
+/// 未配置并发数时使用的默认值
+pub fn default_concurrency() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
@@ -0,0 +1,275 @@
+use crate::cancel::Watchdog;
+use crate::sparse::Sparse;
+use git2::build::CheckoutBuilder;
+use git2::RemoteCallbacks;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 同步所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Clone,
+    Fetch,
+    Merge,
+    Checkout,
+    Hooks,
+    Done,
+}
+
+/// 传输进度, 字段与 `git2::Progress` 一致
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Transfer {
+    pub total_objects: usize,
+    pub received_objects: usize,
+    pub indexed_objects: usize,
+    pub local_objects: usize,
+    pub total_deltas: usize,
+    pub indexed_deltas: usize,
+    pub received_bytes: usize,
+}
+
+impl From<git2::Progress<'_>> for Transfer {
+    fn from(p: git2::Progress<'_>) -> Self {
+        Transfer {
+            total_objects: p.total_objects(),
+            received_objects: p.received_objects(),
+            indexed_objects: p.indexed_objects(),
+            local_objects: p.local_objects(),
+            total_deltas: p.total_deltas(),
+            indexed_deltas: p.indexed_deltas(),
+            received_bytes: p.received_bytes(),
+        }
+    }
+}
+
+/// 接收 clone, fetch 和 checkout 的进度事件, `repo` 是清单中的仓库路径
+pub trait Progress: Send + Sync {
+    fn phase(&self, repo: &str, phase: Phase);
+    fn transfer(&self, repo: &str, stats: &Transfer);
+    fn checkout(&self, repo: &str, completed: usize, total: usize);
+    /// 合并和变基过程中的说明, 比如快进或变基失败的原因
+    fn message(&self, repo: &str, message: &str);
+}
+
+/// 不输出任何进度
+pub struct Silent;
+
+impl Progress for Silent {
+    fn phase(&self, _repo: &str, _phase: Phase) {}
+    fn transfer(&self, _repo: &str, _stats: &Transfer) {}
+    fn checkout(&self, _repo: &str, _completed: usize, _total: usize) {}
+    fn message(&self, _repo: &str, _message: &str) {}
+}
+
+/// 在终端 (stderr) 上绘制进度条, 每 100ms 最多刷新一次
+pub struct Terminal {
+    last: Mutex<Option<Instant>>,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Terminal {
+            last: Mutex::new(None),
+        }
+    }
+}
+
+impl Terminal {
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    fn throttle(&self, done: bool) -> bool {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if !done && last.is_some_and(|t| t.elapsed() < Self::INTERVAL) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+
+    fn bar(completed: usize, total: usize) -> String {
+        const WIDTH: usize = 24;
+        let filled = (completed * WIDTH).checked_div(total).unwrap_or(0);
+        format!(
+            "[{}{}] {:>3}%",
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            (completed * 100).checked_div(total).unwrap_or(0)
+        )
+    }
+
+    fn draw(&self, line: String, done: bool) {
+        let mut err = io::stderr().lock();
+        let _ = write!(err, "\r{}\x1b[K", line);
+        if done {
+            let _ = writeln!(err);
+        }
+        let _ = err.flush();
+    }
+}
+
+impl Progress for Terminal {
+    fn phase(&self, repo: &str, phase: Phase) {
+        if phase != Phase::Done {
+            self.draw(format!("[{}] {:?}", repo, phase), true);
+        }
+    }
+
+    fn transfer(&self, repo: &str, stats: &Transfer) {
+        if stats.total_objects == 0 {
+            return;
+        }
+        let (line, done) = if stats.received_objects == stats.total_objects {
+            let line = format!(
+                "[{}] Resolving deltas {} {}/{}",
+                repo,
+                Self::bar(stats.indexed_deltas, stats.total_deltas),
+                stats.indexed_deltas,
+                stats.total_deltas
+            );
+            (line, stats.indexed_deltas == stats.total_deltas)
+        } else {
+            let line = format!(
+                "[{}] Receiving objects {} {}/{} ({} indexed, {} local), {} KiB",
+                repo,
+                Self::bar(stats.received_objects, stats.total_objects),
+                stats.received_objects,
+                stats.total_objects,
+                stats.indexed_objects,
+                stats.local_objects,
+                stats.received_bytes / 1024
+            );
+            (line, false)
+        };
+        if self.throttle(done) {
+            self.draw(line, done);
+        }
+    }
+
+    fn checkout(&self, repo: &str, completed: usize, total: usize) {
+        let done = completed == total;
+        if self.throttle(done) {
+            let line = format!(
+                "[{}] Checking out {} {}/{}",
+                repo,
+                Self::bar(completed, total),
+                completed,
+                total
+            );
+            self.draw(line, done);
+        }
+    }
+
+    fn message(&self, repo: &str, message: &str) {
+        self.draw(format!("[{}] {}", repo, message), true);
+    }
+}
+
+/// 每个事件输出一行 JSON 到 stderr, 供其它程序解析
+pub struct JsonLines;
+
+impl JsonLines {
+    fn emit(&self, value: serde_json::Value) {
+        let mut err = io::stderr().lock();
+        let _ = writeln!(err, "{}", value);
+    }
+}
+
+impl Progress for JsonLines {
+    fn phase(&self, repo: &str, phase: Phase) {
+        self.emit(serde_json::json!({ "repo": repo, "event": "phase", "phase": phase }));
+    }
+
+    fn transfer(&self, repo: &str, stats: &Transfer) {
+        self.emit(serde_json::json!({ "repo": repo, "event": "transfer", "stats": stats }));
+    }
+
+    fn checkout(&self, repo: &str, completed: usize, total: usize) {
+        self.emit(serde_json::json!({
+            "repo": repo,
+            "event": "checkout",
+            "completed": completed,
+            "total": total,
+        }));
+    }
+
+    fn message(&self, repo: &str, message: &str) {
+        self.emit(serde_json::json!({ "repo": repo, "event": "message", "message": message }));
+    }
+}
+
+/// 绑定到某个仓库的进度输出, 用来给 libgit2 的回调和 CheckoutBuilder 安装进度处理.
+/// 设置了 `sparse` 时, 创建的 CheckoutBuilder 只检出稀疏范围内的文件; 设置了
+/// `watchdog` 时, 传输回调由它决定是否中止
+#[derive(Clone, Copy)]
+pub struct Reporter<'a> {
+    pub repo: &'a str,
+    pub progress: &'a dyn Progress,
+    pub sparse: Option<&'a Sparse>,
+    pub watchdog: Option<&'a Watchdog>,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(repo: &'a str, progress: &'a dyn Progress) -> Reporter<'a> {
+        Reporter {
+            repo,
+            progress,
+            sparse: None,
+            watchdog: None,
+        }
+    }
+
+    pub fn with_sparse(self, sparse: &'a Sparse) -> Reporter<'a> {
+        Reporter {
+            sparse: Some(sparse),
+            ..self
+        }
+    }
+
+    pub fn with_watchdog(self, watchdog: &'a Watchdog) -> Reporter<'a> {
+        Reporter {
+            watchdog: Some(watchdog),
+            ..self
+        }
+    }
+
+    pub fn phase(&self, phase: Phase) {
+        self.progress.phase(self.repo, phase);
+    }
+
+    pub fn transfer(&self, stats: &Transfer) {
+        self.progress.transfer(self.repo, stats);
+    }
+
+    pub fn message(&self, message: &str) {
+        self.progress.message(self.repo, message);
+    }
+
+    pub fn install(&self, cb: &mut RemoteCallbacks<'a>) {
+        let r = *self;
+        cb.transfer_progress(move |stats| {
+            let stats = stats.into();
+            r.transfer(&stats);
+            r.watchdog.is_none_or(|w| w.tick(Some(&stats)))
+        });
+        if let Some(watchdog) = self.watchdog {
+            // 远端在准备数据时只发旁带消息, 也借此检查超时和取消
+            cb.sideband_progress(move |_| watchdog.tick(None));
+        }
+    }
+
+    /// 带进度回调和稀疏路径过滤的 CheckoutBuilder, 检出策略由调用方继续设置
+    pub fn checkout_builder(&self) -> CheckoutBuilder<'a> {
+        let r = *self;
+        let mut cb = CheckoutBuilder::new();
+        cb.progress(move |_path, completed, total| {
+            r.progress.checkout(r.repo, completed, total);
+        });
+        if let Some(sparse) = self.sparse {
+            sparse.filter(&mut cb);
+        }
+        cb
+    }
+}
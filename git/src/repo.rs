@@ -0,0 +1,1710 @@
+use crate::cache::{self, ObjectCache};
+use crate::cancel::{self, Watchdog};
+use crate::changelog::Changelog;
+use crate::credentials::Credentials;
+use crate::error::SyncError;
+use crate::hooks::{HookReport, Hooks};
+use crate::merge::{self, do_merge, do_rebase, MergeOutcome, MergeStrategy, PullMode};
+use crate::pin::{Pin, PinOutcome};
+use crate::policy::PolicyHost;
+use crate::progress::{Phase, Progress, Reporter};
+use crate::retry::RetryPolicy;
+use crate::sparse::{self, Sparse};
+use git2::build::RepoBuilder;
+use git2::{
+    AutotagOption, Commit, Direction, Error, ErrorClass, ErrorCode, FetchOptions, FetchPrune,
+    ObjectType, Oid, RemoteCallbacks, Repository, RepositoryState, ResetType, Status,
+    StatusOptions, Submodule, SubmoduleUpdateOptions,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 以 `refspecs` 创建远端, 第一个之外的 refspec 追加到配置后重新读取
+fn create_remote<'r>(
+    repo: &'r Repository,
+    name: &str,
+    url: &str,
+    refspecs: &[String],
+) -> Result<git2::Remote<'r>, git2::Error> {
+    let Some((first, rest)) = refspecs.split_first() else {
+        return repo.remote(name, url);
+    };
+    let remote = repo.remote_with_fetch(name, url, first)?;
+    if rest.is_empty() {
+        return Ok(remote);
+    }
+    for refspec in rest {
+        repo.remote_add_fetch(name, refspec)?;
+    }
+    repo.find_remote(name)
+}
+
+/// 浅克隆仓库的 .git/shallow. libgit2 1.7 每次拉取后都用服务端本次返回的边界覆盖该文件,
+/// 不带 depth 的拉取会把它清空, 原来的边界提交于是变成缺少父提交的普通提交. 拉取前记下
+/// 原来的边界, 拉取后合并回去. 已打开的 `Repository` 仍缓存着拉取时改写的边界,
+/// 之后遍历历史前要重新打开仓库
+struct ShallowRoots {
+    file: PathBuf,
+    roots: Option<String>,
+}
+
+impl ShallowRoots {
+    fn save(repo: &Repository) -> io::Result<ShallowRoots> {
+        let file = repo.path().join("shallow");
+        let roots = if repo.is_shallow() {
+            Some(fs::read_to_string(&file)?)
+        } else {
+            None
+        };
+        Ok(ShallowRoots { file, roots })
+    }
+
+    fn restore(self) -> io::Result<()> {
+        let Some(before) = self.roots else {
+            return Ok(());
+        };
+        let after = match fs::read_to_string(&self.file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut roots: Vec<&str> = before
+            .lines()
+            .chain(after.lines())
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        roots.sort_unstable();
+        roots.dedup();
+        fs::write(&self.file, roots.join("\n") + "\n")
+    }
+}
+
+/// 各拉取路径之间不同的设置, 其余的 (凭据, 进度, 看门狗, 浅克隆边界) 都由
+/// [`Repo::fetch_once`] 统一处理
+#[derive(Debug, Clone, Copy, Default)]
+struct FetchMode {
+    /// 按 `depth` 浅拉取, 只用于首次克隆
+    shallow: bool,
+    /// 删除远端已不存在的远端跟踪引用
+    prune: bool,
+    /// 获取完整历史和全部标签, 不受 `depth` 和 `tags` 影响, 用于共享缓存
+    complete: bool,
+}
+
+/// FETCH_HEAD 中待合并的提交
+fn merge_head(repo: &git2::Repository) -> Result<git2::AnnotatedCommit<'_>, SyncError> {
+    // 自动跟随的标签也会写入 FETCH_HEAD, 取其中标记为待合并的分支
+    let mut merge_head = None;
+    repo.fetchhead_foreach(|name, url, id, is_merge| {
+        if is_merge {
+            merge_head = Some((
+                name.to_string(),
+                String::from_utf8_lossy(url).to_string(),
+                *id,
+            ));
+        }
+        !is_merge
+    })?;
+    match merge_head {
+        Some((name, url, id)) => Ok(repo.annotated_commit_from_fetchhead(&name, &url, &id)?),
+        None => Err(Error::from_str("FETCH_HEAD 中没有可合并的分支").into()),
+    }
+}
+
+/// 同步时共享的环境: 凭据, 进度输出, 共享对象缓存和策略脚本
+pub struct SyncContext {
+    pub creds: Credentials,
+    pub progress: Box<dyn Progress>,
+    /// 清单中配置了 `cache` 时, 开启 `shared` 的仓库从这里引用对象
+    pub cache: Option<ObjectCache>,
+    /// 拉取到新提交时询问是否接受以及冲突的处理方式
+    pub policy: Option<PolicyHost>,
+}
+
+impl SyncContext {
+    pub fn reporter<'a>(&'a self, repo: &'a str) -> Reporter<'a> {
+        Reporter::new(repo, self.progress.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Repo {
+    pub url: String,
+    pub path: String,
+    pub branch: String,
+    #[serde(default)]
+    pub options: RepoOptions,
+}
+
+/// 传输停滞检测的默认时长 (秒)
+pub const DEFAULT_STALL_TIMEOUT: u64 = 120;
+
+/// 单个仓库的可选配置, 清单中缺省的字段取默认值
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoOptions {
+    /// 拉取使用的远端名称
+    pub remote: String,
+    /// 工作区有本地修改时的处理方式, 默认 abort, 不会丢弃本地修改
+    pub dirty: DirtyPolicy,
+    /// 合并冲突时的处理方式
+    pub merge: MergeStrategy,
+    /// 拉取时合并还是变基
+    pub pull: PullMode,
+    /// 浅克隆的提交深度, 不设置时获取完整历史. 之后的拉取只获取新提交, 保留原来的浅克隆边界
+    pub depth: Option<u32>,
+    /// 克隆时只配置并获取 `branch` 一个分支
+    pub single_branch: bool,
+    /// 是否获取所有标签, 为 false 时不下载任何标签
+    pub tags: bool,
+    /// 稀疏检出的路径范围, 克隆和每次拉取后都会应用
+    pub sparse: Sparse,
+    /// 克隆和每次拉取后递归初始化并更新子模块
+    pub submodules: bool,
+    /// 镜像模式: 维护一个裸仓库, 镜像远端的全部引用并删除远端已删除的引用,
+    /// 忽略 `branch` 和工作区相关的设置
+    pub mirror: bool,
+    /// 固定到标签, 提交或满足版本要求的最新标签, 设置后不再跟随 `branch`
+    pub pin: Option<Pin>,
+    /// 首次克隆的方式
+    pub clone: CloneStrategy,
+    /// 通过 alternates 引用清单 `cache` 目录中按地址共享的对象库, 每次同步前先更新缓存.
+    /// 克隆时总是先 init 再拉取 (忽略 `clone`), 缓存中已有的对象不会再下载. 清单没有配置
+    /// `cache` 时该仓库以配置错误失败
+    pub shared: bool,
+    /// 远端的 fetch refspec, 克隆和每次拉取前都会写入远端配置, 必须包含 `branch`.
+    /// 不设置时按 `single_branch` 只取 `branch` 或取全部分支
+    pub refspecs: Vec<String>,
+    /// 克隆和拉取遇到临时网络错误时的重试策略
+    pub retry: RetryPolicy,
+    /// 单次克隆或拉取的传输时长上限 (秒), 超过后中止并按临时错误重试
+    pub timeout: Option<u64>,
+    /// 传输超过这么多秒没有收到数据就中止, 为 0 时不检测
+    pub stall_timeout: u64,
+    /// 同步后执行的命令, 镜像模式下不执行
+    pub hooks: Hooks,
+    /// daemon 模式下的同步间隔 (秒), 不设置时取清单中的 interval
+    pub interval: Option<u64>,
+}
+
+impl Default for RepoOptions {
+    fn default() -> Self {
+        RepoOptions {
+            remote: "origin".to_string(),
+            dirty: DirtyPolicy::default(),
+            merge: MergeStrategy::Abort,
+            pull: PullMode::Merge,
+            depth: None,
+            single_branch: false,
+            tags: true,
+            sparse: Sparse::default(),
+            submodules: false,
+            mirror: false,
+            pin: None,
+            clone: CloneStrategy::Builder,
+            shared: false,
+            refspecs: vec![],
+            retry: RetryPolicy::default(),
+            timeout: None,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            hooks: Hooks::default(),
+            interval: None,
+        }
+    }
+}
+
+impl RepoOptions {
+    /// 按 `timeout` 和 `stall_timeout` 创建一次传输用的看门狗
+    fn watchdog(&self) -> Watchdog {
+        Watchdog::new(
+            self.timeout.map(Duration::from_secs),
+            (self.stall_timeout > 0).then(|| Duration::from_secs(self.stall_timeout)),
+        )
+    }
+
+    /// 只在首次克隆时设置深度. 之后的拉取不带 depth, libgit2 把 .git/shallow 中的边界发给
+    /// 服务端, 只下载新提交; 带 depth 拉取会用新的提交替换原来的边界, 留下缺少父提交的历史
+    fn shallow(&self, fo: &mut FetchOptions) {
+        if let Some(depth) = self.depth {
+            fo.depth(depth.try_into().unwrap_or(i32::MAX));
+        }
+    }
+
+    /// 把标签设置应用到 clone 与 fetch 共用的 FetchOptions
+    fn apply(&self, fo: &mut FetchOptions) {
+        fo.download_tags(if self.tags {
+            AutotagOption::All
+        } else {
+            AutotagOption::None
+        });
+    }
+}
+
+/// 首次克隆的方式, 之后的拉取都由 libgit2 完成
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneStrategy {
+    /// git2 的 RepoBuilder
+    Builder,
+    /// 先 init 并按 `refspecs` 配置远端, 再 fetch 并检出 `branch`
+    Download,
+    /// 调用 git 命令行
+    Cmd,
+}
+
+/// 拉取前工作区有未提交修改时的处理方式, `pull` 命令和清单共用同一个默认值
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DirtyPolicy {
+    /// 不拉取, 返回 [`SyncError::DirtyTree`]
+    #[default]
+    Abort,
+    /// 暂存本地修改, 拉取后重新应用
+    Stash,
+    /// 硬重置丢弃本地修改
+    Discard,
+}
+
+/// 一次同步的结果
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// 同步前工作区中有修改的文件
+    pub dirty: Vec<String>,
+    /// 对这些修改采取的处理, 工作区干净时为 `None`
+    pub dirty_action: Option<DirtyPolicy>,
+    /// 拉取的合并结果, 新克隆或固定版本时为 `None`
+    pub merge: Option<MergeOutcome>,
+    /// 固定版本的检出结果
+    pub pin: Option<PinOutcome>,
+    /// 清单中的分支改变后切换前所在的分支, 分离 HEAD 时为提交 id
+    pub switched_from: Option<String>,
+    /// 同步前后 HEAD 之间的新提交和文件变化, HEAD 没有移动时为 `None`
+    pub changelog: Option<Changelog>,
+    /// 递归同步的子模块, 单个子模块失败不影响父仓库的结果
+    pub submodules: Vec<SubmoduleSync>,
+    /// 镜像模式下引用的变化
+    pub mirror: Option<MirrorReport>,
+    /// 同步后命令的结果, 没有配置命令或 HEAD 没有移动时为 `None`
+    pub hooks: Option<HookReport>,
+}
+
+/// 同步前的 HEAD, 同步后命令失败时回滚到这里
+struct HeadState {
+    id: Oid,
+    /// HEAD 指向的分支, 分离 HEAD 时为 `None`
+    branch: Option<String>,
+}
+
+impl HeadState {
+    fn read(repo: &Repository) -> Option<HeadState> {
+        let head = repo.head().ok()?;
+        Some(HeadState {
+            id: head.target()?,
+            branch: head
+                .is_branch()
+                .then(|| head.name().map(str::to_string))
+                .flatten(),
+        })
+    }
+}
+
+/// 一次镜像同步中新增, 更新和删除的引用
+#[derive(Debug, Default)]
+pub struct MirrorReport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub pruned: Vec<String>,
+}
+
+impl MirrorReport {
+    fn diff(before: &HashMap<String, Oid>, after: &HashMap<String, Oid>) -> MirrorReport {
+        let mut report = MirrorReport::default();
+        for (name, id) in after {
+            match before.get(name) {
+                None => report.created.push(name.clone()),
+                Some(old) if old != id => report.updated.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        report.pruned = before
+            .keys()
+            .filter(|name| !after.contains_key(*name))
+            .cloned()
+            .collect();
+        report.created.sort();
+        report.updated.sort();
+        report.pruned.sort();
+        report
+    }
+}
+
+/// 仓库中所有直接引用及其指向的对象, 符号引用不计入
+fn direct_refs(repo: &Repository) -> Result<HashMap<String, Oid>, git2::Error> {
+    let mut refs = HashMap::new();
+    for r in repo.references()? {
+        let r = r?;
+        if let (Some(name), Some(id)) = (r.name(), r.target()) {
+            refs.insert(name.to_string(), id);
+        }
+    }
+    Ok(refs)
+}
+
+/// 一个子模块的同步结果, `path` 以清单中的仓库路径开头, 嵌套的子模块逐级拼接
+#[derive(Debug)]
+pub struct SubmoduleSync {
+    pub path: String,
+    pub result: Result<(), SyncError>,
+}
+
+/// 递归初始化并更新 `repo` 的子模块, 检出父仓库记录的提交. 凭据, 进度输出和传输超时
+/// 与父仓库相同, 每个子模块的失败单独记录, 不影响其它子模块. 稀疏检出跳过的子模块不处理
+fn update_submodules(
+    repo: &Repository,
+    prefix: &str,
+    options: &RepoOptions,
+    ctx: &SyncContext,
+) -> Result<Vec<SubmoduleSync>, git2::Error> {
+    let index = repo.index()?;
+    let mut synced = vec![];
+    for mut sm in repo.submodules()? {
+        if sparse::is_skipped(&index, sm.path()) {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, sm.path().display());
+        let result = update_submodule(&mut sm, &path, options, ctx)
+            .and_then(|sub| Ok(update_submodules(&sub, &path, options, ctx)?));
+        match result {
+            Ok(nested) => {
+                synced.push(SubmoduleSync {
+                    path,
+                    result: Ok(()),
+                });
+                synced.extend(nested);
+            }
+            Err(e) => synced.push(SubmoduleSync {
+                path,
+                result: Err(e),
+            }),
+        }
+    }
+    Ok(synced)
+}
+
+fn update_submodule(
+    sm: &mut Submodule,
+    label: &str,
+    options: &RepoOptions,
+    ctx: &SyncContext,
+) -> Result<Repository, SyncError> {
+    let watchdog = options.watchdog();
+    let reporter = ctx.reporter(label).with_watchdog(&watchdog);
+    let mut cb = RemoteCallbacks::new();
+    // 子模块还没有克隆时没有自己的配置
+    let repo = sm.open().ok();
+    if let Some(url) = sm.url() {
+        ctx.creds.install(url, repo.as_ref(), &mut cb);
+    }
+    reporter.install(&mut cb);
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(cb);
+    let mut opts = SubmoduleUpdateOptions::new();
+    opts.fetch(fo).checkout(reporter.checkout_builder());
+    reporter.phase(Phase::Fetch);
+    watchdog.check(sm.update(true, Some(&mut opts)))?;
+    Ok(sm.open()?)
+}
+
+/// 列出已跟踪文件中有修改 (暂存区或工作区) 的路径, 未跟踪和忽略的文件不计入.
+/// 稀疏检出跳过的文件 (skip-worktree) 不在工作区中, 也不算作删除; 子模块由
+/// [`RepoOptions::submodules`] 单独同步, 检出不会覆盖其内容, 也不计入
+fn dirty_paths(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(false)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut opts))?;
+    let index = repo.index()?;
+    Ok(statuses
+        .iter()
+        .filter(|e| e.status() != Status::CURRENT)
+        .filter_map(|e| e.path().map(str::to_string))
+        .filter(|path| !sparse::is_skipped(&index, Path::new(path)))
+        .collect())
+}
+
+/// 上次成功同步的时间记录在仓库自己的配置中, 供 status 读取
+const LAST_SYNC_KEY: &str = "rust-demo.lastsync";
+
+fn record_sync(repo: &Repository) -> Result<(), git2::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    repo.config()?.set_i64(LAST_SYNC_KEY, now)
+}
+
+/// 上次成功同步的时间, Unix 秒
+pub fn last_sync(repo: &Repository) -> Option<i64> {
+    repo.config().ok()?.get_i64(LAST_SYNC_KEY).ok()
+}
+
+impl Repo {
+    fn reporter<'a>(&'a self, ctx: &'a SyncContext) -> Reporter<'a> {
+        ctx.reporter(&self.path).with_sparse(&self.options.sparse)
+    }
+
+    /// 按稀疏配置整理工作区和索引
+    fn apply_sparse(&self, repo: &Repository, reporter: Reporter) -> Result<(), SyncError> {
+        let skipped = self
+            .options
+            .sparse
+            .apply(repo, reporter.checkout_builder())?;
+        if skipped > 0 {
+            println!("[{}] 稀疏检出, 跳过 {} 个文件", self.path, skipped);
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> Result<Repository, SyncError> {
+        Repository::open(path).map_err(|e| SyncError::open(path, e))
+    }
+
+    fn reset(&self, repo: &Repository) -> Result<(), SyncError> {
+        repo.reset(&repo.revparse_single("HEAD")?, ResetType::Hard, None)?;
+        Ok(())
+    }
+
+    fn stash(&self, repo: &mut Repository) -> Result<(), SyncError> {
+        let sig = merge::signature(repo)?;
+        repo.stash_save(&sig, "rust-demo: 拉取前暂存本地修改", None)?;
+        Ok(())
+    }
+
+    /// 重新应用 stash@{0}. 有冲突时把工作区恢复到 HEAD, 修改保留在 stash 中
+    fn unstash(&self, repo: &mut Repository, dirty: &[String]) -> Result<(), SyncError> {
+        if repo.stash_apply(0, None).is_err() {
+            self.reset(repo)?;
+            return Err(SyncError::StashConflict {
+                paths: dirty.to_vec(),
+            });
+        }
+        let index = repo.index()?;
+        if index.has_conflicts() {
+            let paths = merge::conflicts(&index)?
+                .into_iter()
+                .map(|c| c.path)
+                .collect();
+            self.reset(repo)?;
+            return Err(SyncError::StashConflict { paths });
+        }
+        repo.stash_drop(0)?;
+        Ok(())
+    }
+
+    /// 拉取前按 [`DirtyPolicy`] 处理工作区中的本地修改, 再拉取; 暂存的修改在拉取后恢复
+    pub fn update(&self, path: &Path, ctx: &SyncContext) -> Result<SyncReport, SyncError> {
+        let mut repo = self.open(path)?;
+        let mut report = SyncReport {
+            dirty: dirty_paths(&repo)?,
+            ..Default::default()
+        };
+        if !report.dirty.is_empty() {
+            let policy = self.options.dirty;
+            println!(
+                "[{}] 工作区有 {} 个文件被修改, 处理方式: {:?}",
+                self.path,
+                report.dirty.len(),
+                policy
+            );
+            match policy {
+                DirtyPolicy::Abort => {
+                    return Err(SyncError::DirtyTree {
+                        paths: report.dirty,
+                    })
+                }
+                DirtyPolicy::Stash => self.stash(&mut repo)?,
+                DirtyPolicy::Discard => self.reset(&repo)?,
+            }
+            report.dirty_action = Some(policy);
+        }
+
+        let before = repo.head().ok().and_then(|h| h.target());
+        drop(repo);
+        let synced = match &self.options.pin {
+            Some(pin) => self
+                .checkout_pin(path, pin, ctx)
+                .map(|p| report.pin = Some(p)),
+            None => self.pull(path, ctx).map(|(m, from)| {
+                report.merge = Some(m);
+                report.switched_from = from;
+            }),
+        };
+        if report.dirty_action == Some(DirtyPolicy::Stash) {
+            // 拉取失败时也要恢复, 不把用户的修改留在 stash 里.
+            // 重新打开仓库, 避免沿用拉取前缓存的索引
+            let mut repo = self.open(path)?;
+            if repo.state() == RepositoryState::Clean {
+                self.unstash(&mut repo, &report.dirty)?;
+            } else {
+                // merge = "leave" 留下了未完成的合并, 不在其上恢复修改
+                println!("[{}] 合并未完成, 本地修改保留在 stash@{{0}}", self.path);
+            }
+        }
+        synced?;
+
+        let after = self.open(path)?.head().ok().and_then(|h| h.target());
+        if let (Some(from), Some(to)) = (before, after) {
+            if from != to {
+                report.changelog = Some(Changelog::between(&self.open(path)?, from, to)?);
+            }
+        }
+        Ok(report)
+    }
+
+    /// 克隆到 `path`, 临时错误按 [`RetryPolicy`] 重试. 失败的克隆留下的目录会让
+    /// 下一次同步误以为已经克隆过, 所以每次失败后都删除本次创建的目录
+    pub fn clone(&self, ctx: &SyncContext) -> Result<(), SyncError> {
+        self.clone_with(ctx, None)
+    }
+
+    /// 克隆, `objects` 为共享缓存的对象目录时引用其中的对象
+    fn clone_with(&self, ctx: &SyncContext, objects: Option<&Path>) -> Result<(), SyncError> {
+        let path = Path::new(&self.path);
+        let existed = path.exists();
+        self.options.retry.run(&self.path, "clone", || {
+            let result = self.clone_once(ctx, objects);
+            if result.is_err() && !existed && path.exists() {
+                fs::remove_dir_all(path)?;
+            }
+            result
+        })
+    }
+
+    fn clone_once(&self, ctx: &SyncContext, objects: Option<&Path>) -> Result<(), SyncError> {
+        let watchdog = self.options.watchdog();
+        let reporter = self.reporter(ctx).with_watchdog(&watchdog);
+        reporter.phase(Phase::Clone);
+        let repo = match (objects, self.options.clone) {
+            (Some(_), _) | (None, CloneStrategy::Download) => {
+                self.download(ctx, reporter, &watchdog, objects)?
+            }
+            (None, CloneStrategy::Builder) => {
+                watchdog.check(self.clone_with_builder(ctx, reporter))?
+            }
+            (None, CloneStrategy::Cmd) => self.clone_with_cmd()?,
+        };
+        self.track(&repo)?;
+        if self.options.sparse.is_enabled() {
+            self.apply_sparse(&repo, reporter)?;
+        }
+        Ok(())
+    }
+
+    fn clone_with_builder<'a>(
+        &self,
+        ctx: &'a SyncContext,
+        reporter: Reporter<'a>,
+    ) -> Result<Repository, git2::Error> {
+        let remote = self.options.remote.as_str();
+        let refspecs = self.fetch_refspecs();
+        let mut rb = RepoBuilder::new();
+        let mut fo = FetchOptions::new();
+        let mut rc = RemoteCallbacks::new();
+        reporter.install(&mut rc);
+        ctx.creds.install(&self.url, None, &mut rc);
+        fo.remote_callbacks(rc);
+        self.options.apply(&mut fo);
+        self.options.shallow(&mut fo);
+        rb.fetch_options(fo)
+            .branch(&self.branch)
+            .remote_create(move |repo, name, url| {
+                let name = if name == "origin" { remote } else { name };
+                match &refspecs {
+                    Some(refspecs) => create_remote(repo, name, url, refspecs),
+                    None => repo.remote(name, url),
+                }
+            })
+            .with_checkout(reporter.checkout_builder())
+            // .clone_local(CloneLocal::Auto)
+            .clone(&self.url, self.path.as_ref())
+    }
+
+    /// 先 init 再 fetch: 按 refspec 配置远端并拉取, 再从 `branch` 的远端跟踪引用创建
+    /// 本地分支并检出. 与 builder 相比可以在拉取前完全控制远端配置, 也可以在拉取前
+    /// 通过 alternates 引用共享缓存的对象目录 `objects`
+    fn download(
+        &self,
+        ctx: &SyncContext,
+        reporter: Reporter,
+        watchdog: &Watchdog,
+        objects: Option<&Path>,
+    ) -> Result<Repository, SyncError> {
+        let mut repo = Repository::init(&self.path)?;
+        if let Some(objects) = objects {
+            cache::link(&repo, objects)?;
+            // 重新打开才会读取新写入的 alternates
+            repo = self.open(Path::new(&self.path))?;
+        }
+        self.fetch_and_checkout(&repo, ctx, reporter, watchdog)?;
+        Ok(repo)
+    }
+
+    fn fetch_and_checkout(
+        &self,
+        repo: &Repository,
+        ctx: &SyncContext,
+        reporter: Reporter,
+        watchdog: &Watchdog,
+    ) -> Result<(), SyncError> {
+        let name = &self.options.remote;
+        let refspecs = self
+            .fetch_refspecs()
+            .unwrap_or_else(|| vec![format!("+refs/heads/*:refs/remotes/{}/*", name)]);
+        let mut remote = create_remote(repo, name, &self.url, &refspecs)?;
+        let mode = FetchMode {
+            shallow: true,
+            ..Default::default()
+        };
+        self.fetch_once(repo, &mut remote, &[], ctx, watchdog, mode)?;
+
+        let refname = format!("refs/heads/{}", self.branch);
+        let tracking = remote
+            .refspecs()
+            .filter(|r| r.direction() == Direction::Fetch)
+            .find(|r| r.src_matches(&refname))
+            .map(|r| r.transform(&refname))
+            .transpose()?;
+        let Some(tracking) = tracking.as_ref().and_then(|t| t.as_str()) else {
+            let msg = format!("refspec 中没有分支 {}", self.branch);
+            return Err(Error::new(ErrorCode::NotFound, ErrorClass::Reference, msg).into());
+        };
+        let commit = repo.find_reference(tracking)?.peel_to_commit()?;
+        repo.branch(&self.branch, &commit, false)?;
+        reporter.phase(Phase::Checkout);
+        repo.checkout_tree(commit.as_object(), Some(reporter.checkout_builder().safe()))?;
+        repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    /// 调用 git 命令行克隆. 使用 git 自己的凭据配置, 不会交互式询问密码;
+    /// 没有进度输出, 也不受 `timeout`/`stall_timeout` 限制
+    fn clone_with_cmd(&self) -> Result<Repository, SyncError> {
+        let mut cmd = process::Command::new("git");
+        cmd.env("GIT_TERMINAL_PROMPT", "0")
+            .args(["clone", "--quiet", "--origin", &self.options.remote])
+            .args(["--branch", &self.branch]);
+        if let Some(depth) = self.options.depth {
+            cmd.arg(format!("--depth={}", depth));
+        }
+        // git 的浅克隆默认只取一个分支, 与 builder 保持一致
+        cmd.arg(if self.options.single_branch {
+            "--single-branch"
+        } else {
+            "--no-single-branch"
+        });
+        if !self.options.tags {
+            cmd.arg("--no-tags");
+        }
+        let output = cmd.arg("--").arg(&self.url).arg(&self.path).output()?;
+        if !output.status.success() {
+            let msg = format!(
+                "git clone 失败 ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Err(SyncError::Git(Error::from_str(&msg)));
+        }
+        self.open(Path::new(&self.path))
+    }
+
+    /// 创建或更新镜像裸仓库. 首次同步时以 `+refs/*:refs/*` 配置远端, 之后每次
+    /// 拉取全部引用并删除远端已不存在的引用, 其它仓库可以把它当作本地缓存来克隆
+    pub fn mirror(&self, ctx: &SyncContext) -> Result<MirrorReport, SyncError> {
+        let path = Path::new(&self.path);
+        let reporter = ctx.reporter(&self.path);
+        let created = !path.exists();
+        let repo = if !created {
+            let repo = self.open(path)?;
+            if !repo.is_bare() {
+                let e = Error::from_str("镜像模式需要裸仓库, 该路径是带工作区的仓库");
+                return Err(SyncError::open(path, e));
+            }
+            repo
+        } else {
+            reporter.phase(Phase::Clone);
+            let repo = Repository::init_bare(path)?;
+            let remote = &self.options.remote;
+            repo.remote_with_fetch(remote, &self.url, "+refs/*:refs/*")?;
+            repo.config()?
+                .set_bool(&format!("remote.{}.mirror", remote), true)?;
+            repo
+        };
+
+        let before = direct_refs(&repo)?;
+        let mut remote = repo.find_remote(&self.options.remote)?;
+        let mode = FetchMode {
+            shallow: created,
+            prune: true,
+            ..Default::default()
+        };
+        self.do_fetch(&repo, &mut remote, &[], ctx, mode)?;
+        // HEAD 跟随远端的默认分支, 从镜像克隆时检出的就是该分支
+        if let Ok(head) = remote.default_branch() {
+            if let Some(head) = head.as_str() {
+                repo.set_head(head)?;
+            }
+        }
+        Ok(MirrorReport::diff(&before, &direct_refs(&repo)?))
+    }
+
+    /// 开启了 `shared` 时, 创建或更新该地址的缓存并返回其对象目录. 之后工作仓库自己的
+    /// 拉取只会下载缓存中没有的对象. 清单没有配置 `cache` 时返回配置错误
+    fn update_cache(&self, ctx: &SyncContext) -> Result<Option<PathBuf>, SyncError> {
+        if !self.options.shared || self.options.mirror {
+            return Ok(None);
+        }
+        let Some(cache) = ctx.cache.as_ref() else {
+            return Err(SyncError::Config(
+                "开启了 shared, 但清单中没有配置 cache".to_string(),
+            ));
+        };
+        let mode = FetchMode {
+            complete: true,
+            ..Default::default()
+        };
+        let objects = cache.update(&self.url, |repo, remote| {
+            self.do_fetch(repo, remote, &[], ctx, mode)
+        })?;
+        Ok(Some(objects))
+    }
+
+    /// 拉取固定版本所需的引用, 解析出目标提交并以分离 HEAD 检出
+    pub fn checkout_pin(
+        &self,
+        path: &Path,
+        pin: &Pin,
+        ctx: &SyncContext,
+    ) -> Result<PinOutcome, SyncError> {
+        let repo = self.open(path)?;
+        let reporter = self.reporter(ctx);
+        if let Some(refspecs) = pin.refspecs(&repo) {
+            let mut remote = repo.find_remote(&self.options.remote)?;
+            self.do_fetch(&repo, &mut remote, &refspecs, ctx, FetchMode::default())?;
+        }
+
+        let (target, id) = pin.resolve(&repo)?;
+        let from = repo.head().ok().and_then(|h| h.target());
+        if from != Some(id) || repo.head_detached().is_ok_and(|d| !d) {
+            println!("[{}] 检出固定版本 {} ({})", self.path, target, id);
+            reporter.phase(Phase::Checkout);
+            let commit = repo.find_object(id, None)?;
+            repo.checkout_tree(&commit, Some(reporter.checkout_builder().safe()))?;
+            repo.set_head_detached(id)?;
+        }
+        self.apply_sparse(&repo, reporter)?;
+        Ok(PinOutcome {
+            target,
+            from,
+            to: id,
+        })
+    }
+
+    pub fn find_last_commit<'repo>(&self, repo: &'repo Repository) -> Result<Commit<'repo>, Error> {
+        let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
+        match obj.into_commit() {
+            Ok(c) => Ok(c),
+            _ => Err(Error::from_str("commit error")),
+        }
+    }
+
+    /// [`fetch_once`](Repo::fetch_once), 临时错误按 [`RetryPolicy`] 重试, 每次尝试用新的看门狗
+    fn do_fetch(
+        &self,
+        repo: &Repository,
+        remote: &mut git2::Remote,
+        refspecs: &[&str],
+        ctx: &SyncContext,
+        mode: FetchMode,
+    ) -> Result<(), SyncError> {
+        self.options.retry.run(&self.path, "fetch", || {
+            let watchdog = self.options.watchdog();
+            self.fetch_once(repo, remote, refspecs, ctx, &watchdog, mode)
+        })
+    }
+
+    /// 所有拉取共用的一次拉取: 安装凭据和进度回调, 受 `watchdog` 限制, 拉取前后保存和
+    /// 恢复浅克隆边界. `refspecs` 为空时按远端配置的 refspec 拉取
+    fn fetch_once(
+        &self,
+        repo: &Repository,
+        remote: &mut git2::Remote,
+        refspecs: &[&str],
+        ctx: &SyncContext,
+        watchdog: &Watchdog,
+        mode: FetchMode,
+    ) -> Result<(), SyncError> {
+        let reporter = self.reporter(ctx).with_watchdog(watchdog);
+        let mut cb = RemoteCallbacks::new();
+        ctx.creds
+            .install(remote.url().unwrap_or(&self.url), Some(repo), &mut cb);
+        reporter.install(&mut cb);
+        let mut fo = FetchOptions::new();
+        fo.remote_callbacks(cb);
+        if mode.complete {
+            fo.download_tags(AutotagOption::All);
+        } else {
+            self.options.apply(&mut fo);
+            if mode.shallow {
+                self.options.shallow(&mut fo);
+            }
+        }
+        if mode.prune {
+            fo.prune(FetchPrune::On);
+        }
+        reporter.phase(Phase::Fetch);
+        let shallow = ShallowRoots::save(repo)?;
+        let fetched = watchdog.check(remote.fetch(refspecs, Some(&mut fo), None));
+        shallow.restore()?;
+        fetched?;
+        // 最终的统计, 其中 local_objects 是 thin pack 中复用的本地对象数
+        reporter.transfer(&remote.stats().into());
+        Ok(())
+    }
+
+    /// 配置的 refspec, 或只跟踪单个分支时 `branch` 的 refspec; 都没有时为 `None`,
+    /// 沿用远端已有的配置
+    fn fetch_refspecs(&self) -> Option<Vec<String>> {
+        if !self.options.refspecs.is_empty() {
+            return Some(self.options.refspecs.clone());
+        }
+        self.options.single_branch.then(|| {
+            vec![format!(
+                "+refs/heads/{0}:refs/remotes/{1}/{0}",
+                self.branch, self.options.remote
+            )]
+        })
+    }
+
+    /// 把 `branch` 的上游设置为 `<remote>/<branch>`; 配置了 refspec 或只跟踪单个分支时,
+    /// 远端的 refspec 也随之更新
+    fn track(&self, repo: &Repository) -> Result<(), git2::Error> {
+        let (remote, branch) = (&self.options.remote, &self.branch);
+        let mut config = repo.config()?;
+        config.set_str(&format!("branch.{}.remote", branch), remote)?;
+        config.set_str(
+            &format!("branch.{}.merge", branch),
+            &format!("refs/heads/{}", branch),
+        )?;
+        if let Some(refspecs) = self.fetch_refspecs() {
+            let current = repo.find_remote(remote)?.fetch_refspecs()?;
+            if !current
+                .iter()
+                .flatten()
+                .eq(refspecs.iter().map(String::as_str))
+            {
+                if !current.is_empty() {
+                    config.remove_multivar(&format!("remote.{}.fetch", remote), ".*")?;
+                }
+                for refspec in &refspecs {
+                    repo.remote_add_fetch(remote, refspec)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// HEAD 不在 `branch` 上时 (清单中的分支改过, 或刚取消固定版本) 安全地切换过去,
+    /// 本地分支不存在时从拉取到的提交创建. 返回切换前所在的分支
+    fn switch_branch(
+        &self,
+        repo: &Repository,
+        fetched: &git2::AnnotatedCommit,
+        reporter: Reporter,
+    ) -> Result<Option<String>, SyncError> {
+        let refname = format!("refs/heads/{}", self.branch);
+        let head = repo.head().ok();
+        if head.as_ref().and_then(|h| h.name()) == Some(refname.as_str()) {
+            return Ok(None);
+        }
+        let previous = match &head {
+            Some(h) if h.is_branch() => h.shorthand().unwrap_or_default().to_string(),
+            Some(h) => h.target().map(|id| id.to_string()).unwrap_or_default(),
+            None => "-".to_string(),
+        };
+        let target = match repo.find_reference(&refname) {
+            Ok(r) => r.peel_to_commit()?,
+            Err(_) => {
+                let commit = repo.find_commit(fetched.id())?;
+                repo.branch(&self.branch, &commit, false)?;
+                commit
+            }
+        };
+        println!("[{}] 切换分支 {} -> {}", self.path, previous, self.branch);
+        reporter.phase(Phase::Checkout);
+        repo.checkout_tree(target.as_object(), Some(reporter.checkout_builder().safe()))?;
+        repo.set_head(&refname)?;
+        Ok(Some(previous))
+    }
+
+    /// 拉取并合并 `branch`, 返回合并结果和切换前所在的分支 (没有切换时为 `None`)
+    pub fn pull(
+        &self,
+        path: &Path,
+        ctx: &SyncContext,
+    ) -> Result<(MergeOutcome, Option<String>), SyncError> {
+        let repo = self.open(path)?;
+        let reporter = self.reporter(ctx);
+        // 先更新上游和 refspec, 单分支仓库换分支后远端跟踪引用才会随拉取更新
+        self.track(&repo)?;
+        let mut remote = repo.find_remote(&self.options.remote)?;
+        self.do_fetch(
+            &repo,
+            &mut remote,
+            &[&self.branch],
+            ctx,
+            FetchMode::default(),
+        )?;
+        // 浅克隆的边界在拉取时被改写过, 重新打开才能读到恢复后的边界
+        let repo = self.open(path)?;
+        let fetch_commit = merge_head(&repo)?;
+        // 先询问策略再切换分支, 被拒绝时工作区保持原样
+        let mut strategy = self.options.merge;
+        if let Some(policy) = &ctx.policy {
+            strategy = self
+                .consult(policy, &repo, &fetch_commit)?
+                .unwrap_or(strategy);
+        }
+        let switched_from = self.switch_branch(&repo, &fetch_commit, reporter)?;
+        reporter.phase(Phase::Merge);
+        let outcome = match self.options.pull {
+            PullMode::Merge => do_merge(&repo, &self.branch, fetch_commit, strategy, reporter)?,
+            PullMode::Rebase => do_rebase(&repo, &self.branch, fetch_commit, strategy, reporter)?,
+        };
+        // 已是最新时也要应用, 稀疏范围可能在两次同步之间改过
+        self.apply_sparse(&repo, reporter)?;
+        Ok((outcome, switched_from))
+    }
+
+    /// 切换分支和合并前询问策略脚本是否接受 `branch` 到 `fetch_commit` 的新提交,
+    /// 合并会冲突时再询问处理方式. 没有新提交时不调用脚本
+    fn consult(
+        &self,
+        policy: &PolicyHost,
+        repo: &Repository,
+        fetch_commit: &git2::AnnotatedCommit,
+    ) -> Result<Option<MergeStrategy>, SyncError> {
+        let fetched = fetch_commit.id();
+        // 按要合并到的分支判断; 本地还没有该分支时切换会直接在拉取到的提交上创建它,
+        // 相对当前 HEAD 的提交都是新的, 也不会有合并
+        let branch = repo
+            .refname_to_id(&format!("refs/heads/{}", self.branch))
+            .ok();
+        let head = branch.or_else(|| repo.head().ok().and_then(|h| h.target()));
+        let Some(head) = head else {
+            return Ok(None);
+        };
+        if head == fetched || repo.graph_descendant_of(head, fetched)? {
+            return Ok(None);
+        }
+        let log = Changelog::between(repo, head, fetched)?;
+        if let Some(reason) = policy.accept_commits(self, &log)? {
+            return Err(SyncError::Rejected {
+                commits: log.commits.len(),
+                reason,
+            });
+        }
+        if branch.is_none() || repo.graph_descendant_of(fetched, head)? {
+            return Ok(None);
+        }
+        let conflicts = merge::preview_conflicts(repo, head, fetched)?;
+        if conflicts.is_empty() {
+            return Ok(None);
+        }
+        let strategy = policy.on_conflict(self, &conflicts)?;
+        if let Some(strategy) = strategy {
+            println!("[{}] 策略指定冲突处理方式: {:?}", self.path, strategy);
+        }
+        Ok(strategy)
+    }
+
+    /// HEAD 相对 `before` 移动了 (包括首次克隆) 时在工作区执行同步后命令,
+    /// 失败且配置了 `rollback` 时回滚到 `before`
+    fn run_hooks(
+        &self,
+        repo: &Repository,
+        before: Option<&HeadState>,
+        ctx: &SyncContext,
+    ) -> Result<Option<HookReport>, SyncError> {
+        let Some(after) = HeadState::read(repo) else {
+            return Ok(None);
+        };
+        if before.is_some_and(|b| b.id == after.id) {
+            return Ok(None);
+        }
+        let dir = repo.workdir().unwrap_or(repo.path());
+        let env = [
+            ("RUST_DEMO_PATH", self.path.clone()),
+            ("RUST_DEMO_BRANCH", self.branch.clone()),
+            (
+                "RUST_DEMO_FROM",
+                before.map_or_else(String::new, |b| b.id.to_string()),
+            ),
+            ("RUST_DEMO_TO", after.id.to_string()),
+        ];
+        ctx.reporter(&self.path).phase(Phase::Hooks);
+        let mut report = HookReport {
+            results: self.options.hooks.run(dir, &env),
+            ..Default::default()
+        };
+        let Some(failed) = report.failed() else {
+            return Ok(Some(report));
+        };
+        println!("[{}] 同步后命令失败: {}", self.path, failed.command);
+        if self.options.hooks.rollback {
+            match before {
+                None => report.rollback_error = Some("首次克隆, 没有可回滚的 HEAD".to_string()),
+                Some(before) => match self.rollback(repo, before, ctx) {
+                    Ok(()) => {
+                        println!("[{}] 已回滚到 {}", self.path, before.id);
+                        report.rolled_back = Some(before.id.to_string());
+                    }
+                    Err(e) => report.rollback_error = Some(e.to_string()),
+                },
+            }
+        }
+        Ok(Some(report))
+    }
+
+    /// 把工作区和 HEAD 恢复到同步前, HEAD 原来指向的分支也移回原来的提交.
+    /// 使用安全检出, 工作区中同步后被改动的文件会让回滚失败而不是被覆盖
+    fn rollback(
+        &self,
+        repo: &Repository,
+        before: &HeadState,
+        ctx: &SyncContext,
+    ) -> Result<(), SyncError> {
+        let reporter = self.reporter(ctx);
+        reporter.phase(Phase::Checkout);
+        let commit = repo.find_object(before.id, None)?;
+        repo.checkout_tree(&commit, Some(reporter.checkout_builder().safe()))?;
+        match &before.branch {
+            Some(name) => {
+                repo.reference(name, before.id, true, "rust-demo: 同步后命令失败, 回滚")?;
+                repo.set_head(name)?;
+            }
+            None => repo.set_head_detached(before.id)?,
+        }
+        self.apply_sparse(repo, reporter)
+    }
+
+    pub fn check(&self, ctx: &SyncContext) -> Result<SyncReport, SyncError> {
+        // 已经按过 Ctrl-C 时不再开始新的仓库
+        if cancel::requested() {
+            return Err(SyncError::Cancelled);
+        }
+        let repo_path = Path::new(&self.path);
+        let before = if repo_path.exists() && !self.options.mirror {
+            HeadState::read(&self.open(repo_path)?)
+        } else {
+            None
+        };
+        let objects = self.update_cache(ctx)?;
+        if let (Some(objects), true) = (&objects, repo_path.exists()) {
+            if cache::link(&self.open(repo_path)?, objects)? {
+                println!("[{}] 引用共享对象库 {}", self.path, objects.display());
+            }
+        }
+        let mut report = if self.options.mirror {
+            SyncReport {
+                mirror: Some(self.mirror(ctx)?),
+                ..Default::default()
+            }
+        } else if !repo_path.exists() {
+            self.clone_with(ctx, objects.as_deref())?;
+            SyncReport {
+                pin: match &self.options.pin {
+                    Some(pin) => Some(self.checkout_pin(repo_path, pin, ctx)?),
+                    None => None,
+                },
+                ..Default::default()
+            }
+        } else {
+            self.update(repo_path, ctx)?
+        };
+        let repo = self.open(repo_path)?;
+        if self.options.submodules && !self.options.mirror {
+            report.submodules = update_submodules(&repo, &self.path, &self.options, ctx)?;
+        }
+        if !self.options.mirror && !self.options.hooks.post_sync.is_empty() {
+            report.hooks = self.run_hooks(&repo, before.as_ref(), ctx)?;
+        }
+        record_sync(&repo)?;
+        ctx.reporter(&self.path).phase(Phase::Done);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Silent;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    /// 用 `git http-backend` 提供 smart HTTP 服务. libgit2 的本地传输不支持浅克隆,
+    /// 只能通过 HTTP 测试 depth
+    fn serve(root: &Path) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let root = root.to_path_buf();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = respond(stream, &root);
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn respond(mut stream: TcpStream, root: &Path) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().unwrap_or_default().to_string();
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let mut content_type = String::new();
+        let mut length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                match name.to_ascii_lowercase().as_str() {
+                    "content-type" => content_type = value.trim().to_string(),
+                    "content-length" => length = value.trim().parse().unwrap_or(0),
+                    _ => {}
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+
+        let mut child = Command::new("git")
+            .arg("http-backend")
+            .env("GIT_PROJECT_ROOT", root)
+            .env("GIT_HTTP_EXPORT_ALL", "1")
+            .env("REQUEST_METHOD", &method)
+            .env("PATH_INFO", path)
+            .env("QUERY_STRING", query)
+            .env("CONTENT_TYPE", &content_type)
+            .env("CONTENT_LENGTH", length.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(&body)?;
+        let output = child.wait_with_output()?;
+        let split = output
+            .stdout
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or(0);
+        let head = String::from_utf8_lossy(&output.stdout[..split]);
+        let body = &output.stdout[(split + 4).min(output.stdout.len())..];
+        let mut status = "200 OK".to_string();
+        let mut headers = String::new();
+        for header in head.lines() {
+            match header.strip_prefix("Status:") {
+                Some(s) => status = s.trim().to_string(),
+                None => headers.push_str(&format!("{}\r\n", header)),
+            }
+        }
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            headers,
+            body.len()
+        )?;
+        stream.write_all(body)
+    }
+
+    /// 在 master 上追加一个提交, `files` 写入 (覆盖) 上一个提交的树, 不动工作区
+    fn commit(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let parent = repo
+            .refname_to_id("refs/heads/master")
+            .ok()
+            .map(|id| repo.find_commit(id).unwrap());
+        let base = match &parent {
+            Some(parent) => parent.tree().unwrap(),
+            None => repo
+                .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+                .unwrap(),
+        };
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            update.upsert(*path, blob, git2::FileMode::Blob);
+        }
+        let tree = repo
+            .find_tree(update.create_updated(repo, &base).unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("t", "t@t").unwrap();
+        let message: Vec<_> = files.iter().map(|(path, _)| *path).collect();
+        repo.commit(
+            Some("refs/heads/master"),
+            &sig,
+            &sig,
+            &message.join(", "),
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    /// 在工作区中改写文件并提交到当前分支
+    fn commit_local(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let sig = git2::Signature::now("t", "t@t").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "local", &tree, &[&parent])
+            .unwrap()
+    }
+
+    fn context() -> SyncContext {
+        SyncContext {
+            creds: Credentials::default(),
+            progress: Box::new(Silent),
+            cache: None,
+            policy: None,
+        }
+    }
+
+    /// 临时目录中的本地裸上游 `up.git` 和检出目录 `work`, 结束时删除
+    struct Fixture {
+        dir: PathBuf,
+        upstream: Repository,
+        ctx: SyncContext,
+    }
+
+    impl Fixture {
+        /// 上游带着 `files` 的初始提交
+        fn new(name: &str, files: &[(&str, &str)]) -> Fixture {
+            let dir = std::env::temp_dir().join(format!("rust-demo-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let upstream = Repository::init_bare(dir.join("up.git")).unwrap();
+            commit(&upstream, files);
+            Fixture {
+                dir,
+                upstream,
+                ctx: context(),
+            }
+        }
+
+        /// 指向上游的仓库配置, 已经克隆好
+        fn clone(&self, options: RepoOptions) -> Repo {
+            let repo = Repo {
+                url: self.dir.join("up.git").to_string_lossy().to_string(),
+                path: self.dir.join("work").to_string_lossy().to_string(),
+                branch: "master".to_string(),
+                options,
+            };
+            repo.clone(&self.ctx).unwrap();
+            repo
+        }
+
+        fn work(&self) -> Repository {
+            Repository::open(self.dir.join("work")).unwrap()
+        }
+
+        fn update(&self, repo: &Repo) -> Result<SyncReport, SyncError> {
+            repo.update(&self.dir.join("work"), &self.ctx)
+        }
+
+        fn head(&self) -> Oid {
+            self.work().head().unwrap().target().unwrap()
+        }
+
+        /// 工作区中的文件内容, 不存在时为 `None`
+        fn read(&self, path: &str) -> Option<String> {
+            fs::read_to_string(self.dir.join("work").join(path)).ok()
+        }
+
+        fn write(&self, path: &str, content: &str) {
+            fs::write(self.dir.join("work").join(path), content).unwrap();
+        }
+
+        /// 索引中 `path` 的内容
+        fn staged(&self, path: &str) -> String {
+            let work = self.work();
+            let entry = work.index().unwrap().get_path(Path::new(path), 0).unwrap();
+            let blob = work.find_blob(entry.id).unwrap();
+            String::from_utf8_lossy(blob.content()).to_string()
+        }
+
+        /// 工作区是否与 HEAD 一致 (没有修改, 没有冲突)
+        fn clean(&self) -> bool {
+            let work = self.work();
+            dirty_paths(&work).unwrap().is_empty() && !work.index().unwrap().has_conflicts()
+        }
+
+        fn stashes(&self) -> usize {
+            let mut work = self.work();
+            let mut count = 0;
+            work.stash_foreach(|_, _, _| {
+                count += 1;
+                true
+            })
+            .unwrap();
+            count
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn dirty_tree_aborts_by_default() {
+        let fx = Fixture::new("dirty-abort", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions::default());
+        let before = fx.head();
+        commit(&fx.upstream, &[("a", "2")]);
+        fx.write("b", "local");
+
+        let err = fx.update(&repo).unwrap_err();
+        assert!(matches!(err, SyncError::DirtyTree { ref paths } if paths == &["b"]));
+        assert_eq!(fx.head(), before);
+        assert_eq!(fx.read("a").as_deref(), Some("1"));
+        assert_eq!(fx.read("b").as_deref(), Some("local"));
+    }
+
+    #[test]
+    fn stash_restores_local_changes_after_pull() {
+        let fx = Fixture::new("dirty-stash", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions {
+            dirty: DirtyPolicy::Stash,
+            ..Default::default()
+        });
+        let pulled = commit(&fx.upstream, &[("a", "2")]);
+        fx.write("b", "local");
+
+        let report = fx.update(&repo).unwrap();
+        assert_eq!(report.dirty_action, Some(DirtyPolicy::Stash));
+        assert_eq!(fx.head(), pulled);
+        assert_eq!(fx.read("a").as_deref(), Some("2"));
+        assert_eq!(fx.read("b").as_deref(), Some("local"));
+        // 修改恢复到工作区, 没有暂存进索引, stash 已删除
+        assert_eq!(fx.staged("b"), "1");
+        assert_eq!(fx.stashes(), 0);
+    }
+
+    #[test]
+    fn stash_conflict_keeps_changes_in_stash() {
+        let fx = Fixture::new("dirty-stash-conflict", &[("a", "1")]);
+        let repo = fx.clone(RepoOptions {
+            dirty: DirtyPolicy::Stash,
+            ..Default::default()
+        });
+        let pulled = commit(&fx.upstream, &[("a", "upstream")]);
+        fx.write("a", "local");
+
+        let err = fx.update(&repo).unwrap_err();
+        assert!(matches!(err, SyncError::StashConflict { ref paths } if paths == &["a"]));
+        // 拉取已完成, 工作区回到新的 HEAD, 本地修改留在 stash@{0}
+        assert_eq!(fx.head(), pulled);
+        assert_eq!(fx.read("a").as_deref(), Some("upstream"));
+        assert!(fx.clean());
+        assert_eq!(fx.stashes(), 1);
+    }
+
+    /// 本地和上游都改了 `a`, 返回 (本地提交, 上游提交)
+    fn diverge(fx: &Fixture) -> (Oid, Oid) {
+        let local = commit_local(&fx.work(), &[("a", "local")]);
+        let remote = commit(&fx.upstream, &[("a", "upstream"), ("b", "2")]);
+        (local, remote)
+    }
+
+    /// 按 `strategy` 合并冲突, 检查生成了以本地和上游为父提交的合并提交
+    fn merge_with(name: &str, strategy: MergeStrategy) -> (Fixture, MergeOutcome) {
+        let fx = Fixture::new(name, &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions {
+            merge: strategy,
+            ..Default::default()
+        });
+        let (local, remote) = diverge(&fx);
+        let outcome = fx.update(&repo).unwrap().merge.unwrap();
+        let parents: Vec<_> = fx
+            .work()
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .parent_ids()
+            .collect();
+        assert_eq!(parents, vec![local, remote]);
+        (fx, outcome)
+    }
+
+    #[test]
+    fn ours_keeps_local_side_of_conflict() {
+        let (fx, outcome) = merge_with("merge-ours", MergeStrategy::Ours);
+        assert!(
+            matches!(outcome, MergeOutcome::Merged { ref resolved, .. } if resolved.len() == 1)
+        );
+        assert_eq!(fx.read("a").as_deref(), Some("local"));
+        assert_eq!(fx.read("b").as_deref(), Some("2"));
+        assert_eq!(fx.staged("a"), "local");
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn theirs_takes_upstream_side_of_conflict() {
+        let (fx, outcome) = merge_with("merge-theirs", MergeStrategy::Theirs);
+        assert!(
+            matches!(outcome, MergeOutcome::Merged { ref resolved, .. } if resolved.len() == 1)
+        );
+        assert_eq!(fx.read("a").as_deref(), Some("upstream"));
+        assert_eq!(fx.read("b").as_deref(), Some("2"));
+        assert_eq!(fx.staged("a"), "upstream");
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn abort_leaves_conflicting_merge_untouched() {
+        let fx = Fixture::new("merge-abort", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions::default());
+        let (local, _) = diverge(&fx);
+
+        let err = fx.update(&repo).unwrap_err();
+        assert!(matches!(err, SyncError::MergeConflict { .. }));
+        assert_eq!(fx.head(), local);
+        assert_eq!(fx.read("a").as_deref(), Some("local"));
+        assert_eq!(fx.read("b").as_deref(), Some("1"));
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn leave_writes_conflict_markers() {
+        let fx = Fixture::new("merge-leave", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions {
+            merge: MergeStrategy::Leave,
+            ..Default::default()
+        });
+        let (local, remote) = diverge(&fx);
+
+        let err = fx.update(&repo).unwrap_err();
+        assert!(matches!(err, SyncError::MergeConflict { ref conflicts } if conflicts.len() == 1));
+        // HEAD 不动, 索引带冲突, 工作区有冲突标记, 等用户解决后提交
+        let work = fx.work();
+        assert_eq!(fx.head(), local);
+        assert_eq!(work.state(), RepositoryState::Merge);
+        assert_eq!(work.refname_to_id("MERGE_HEAD").unwrap(), remote);
+        assert!(work.index().unwrap().has_conflicts());
+        let a = fx.read("a").unwrap();
+        assert!(a.contains("<<<<<<<") && a.contains("local") && a.contains("upstream"));
+        // 没有冲突的文件照常合并
+        assert_eq!(fx.read("b").as_deref(), Some("2"));
+        assert_eq!(fx.staged("b"), "2");
+    }
+
+    #[test]
+    fn rebase_replays_local_commits_onto_upstream() {
+        let fx = Fixture::new("rebase", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions {
+            pull: PullMode::Rebase,
+            ..Default::default()
+        });
+        commit_local(&fx.work(), &[("a", "local")]);
+        let remote = commit(&fx.upstream, &[("b", "2")]);
+
+        let outcome = fx.update(&repo).unwrap().merge.unwrap();
+        assert!(matches!(outcome, MergeOutcome::Rebased { replayed: 1, .. }));
+        let work = fx.work();
+        let head = work.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_ids().collect::<Vec<_>>(), vec![remote]);
+        assert_eq!(fx.read("a").as_deref(), Some("local"));
+        assert_eq!(fx.read("b").as_deref(), Some("2"));
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn rebase_conflict_aborts() {
+        let fx = Fixture::new("rebase-abort", &[("a", "1"), ("b", "1")]);
+        let repo = fx.clone(RepoOptions {
+            pull: PullMode::Rebase,
+            ..Default::default()
+        });
+        let (local, _) = diverge(&fx);
+
+        let err = fx.update(&repo).unwrap_err();
+        assert!(matches!(err, SyncError::MergeConflict { ref conflicts } if conflicts.len() == 1));
+        // 变基已中止: 分支, 索引和工作区都还是变基前的样子
+        let work = fx.work();
+        assert_eq!(fx.head(), local);
+        assert_eq!(work.state(), RepositoryState::Clean);
+        assert!(!work.path().join("rebase-merge").exists());
+        assert_eq!(fx.read("a").as_deref(), Some("local"));
+        assert_eq!(fx.read("b").as_deref(), Some("1"));
+        assert_eq!(fx.staged("a"), "local");
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn sparse_checkout_skips_and_restores_files() {
+        let fx = Fixture::new(
+            "sparse",
+            &[
+                ("docs/a.md", "1"),
+                ("src/main.rs", "1"),
+                ("src/gen/x.rs", "1"),
+            ],
+        );
+        let sparse = Sparse {
+            include: vec!["src".to_string()],
+            exclude: vec!["src/gen".to_string()],
+        };
+        let repo = fx.clone(RepoOptions {
+            sparse,
+            ..Default::default()
+        });
+        let skipped = |path: &str| sparse::is_skipped(&fx.work().index().unwrap(), Path::new(path));
+        assert_eq!(fx.read("src/main.rs").as_deref(), Some("1"));
+        assert_eq!(fx.read("docs/a.md"), None);
+        assert_eq!(fx.read("src/gen/x.rs"), None);
+        assert!(skipped("docs/a.md") && skipped("src/gen/x.rs") && !skipped("src/main.rs"));
+        // 跳过的文件不算本地删除
+        assert!(fx.clean());
+        assert!(fx.work().path().join("info/sparse-checkout").exists());
+
+        // 拉取后范围外的文件只更新索引
+        let pulled = commit(&fx.upstream, &[("docs/a.md", "2"), ("src/main.rs", "2")]);
+        fx.update(&repo).unwrap();
+        assert_eq!(fx.head(), pulled);
+        assert_eq!(fx.read("src/main.rs").as_deref(), Some("2"));
+        assert_eq!(fx.read("docs/a.md"), None);
+        assert_eq!(fx.staged("docs/a.md"), "2");
+        assert!(skipped("docs/a.md"));
+        assert!(fx.clean());
+
+        // 去掉稀疏配置后恢复完整的工作区
+        let full = Repo {
+            options: RepoOptions::default(),
+            ..repo
+        };
+        fx.update(&full).unwrap();
+        assert_eq!(fx.read("docs/a.md").as_deref(), Some("2"));
+        assert_eq!(fx.read("src/gen/x.rs").as_deref(), Some("1"));
+        assert!(!skipped("docs/a.md") && !skipped("src/gen/x.rs"));
+        let work = fx.work();
+        assert!(!work
+            .config()
+            .unwrap()
+            .get_bool("core.sparseCheckout")
+            .unwrap());
+        assert!(!work.path().join("info/sparse-checkout").exists());
+        assert!(fx.clean());
+    }
+
+    /// 拉取一个新提交后执行失败的同步后命令, 返回 (同步前的 HEAD, 拉取的提交, 命令结果)
+    fn failing_hook(fx: &Fixture, rollback: bool) -> (Oid, Oid, HookReport) {
+        let repo = fx.clone(RepoOptions {
+            hooks: Hooks {
+                post_sync: vec!["exit 3".to_string()],
+                rollback,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let before = fx.head();
+        let pulled = commit(&fx.upstream, &[("a", "2")]);
+        let report = repo.check(&fx.ctx).unwrap().hooks.unwrap();
+        assert_eq!(report.failed().unwrap().code, Some(3));
+        (before, pulled, report)
+    }
+
+    #[test]
+    fn failed_hook_rolls_back() {
+        let fx = Fixture::new("hook-rollback", &[("a", "1")]);
+        let (before, _, report) = failing_hook(&fx, true);
+        assert_eq!(report.rolled_back, Some(before.to_string()));
+        assert_eq!(report.rollback_error, None);
+        // HEAD 仍指向分支, 分支移回同步前的提交
+        let work = fx.work();
+        assert_eq!(work.head().unwrap().name(), Some("refs/heads/master"));
+        assert_eq!(fx.head(), before);
+        assert_eq!(fx.read("a").as_deref(), Some("1"));
+        assert_eq!(fx.staged("a"), "1");
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn failed_hook_without_rollback_keeps_update() {
+        let fx = Fixture::new("hook-keep", &[("a", "1")]);
+        let (_, pulled, report) = failing_hook(&fx, false);
+        assert_eq!(report.rolled_back, None);
+        assert_eq!(fx.head(), pulled);
+        assert_eq!(fx.read("a").as_deref(), Some("2"));
+        assert!(fx.clean());
+    }
+
+    #[test]
+    fn shallow_clone_survives_upstream_push() {
+        if Command::new("git").arg("--version").output().is_err() {
+            eprintln!("没有 git 命令, 跳过");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("rust-demo-shallow-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let upstream = Repository::init_bare(dir.join("up.git")).unwrap();
+        for i in 0..3 {
+            commit(&upstream, &[("file", &format!("c{}", i))]);
+        }
+        let repo = Repo {
+            url: format!("{}/up.git", serve(&dir)),
+            path: dir.join("work").to_string_lossy().to_string(),
+            branch: "master".to_string(),
+            options: RepoOptions {
+                depth: Some(1),
+                ..Default::default()
+            },
+        };
+        let ctx = context();
+        let path = Path::new(&repo.path);
+        repo.clone(&ctx).unwrap();
+        assert!(Repository::open(path).unwrap().is_shallow());
+
+        for i in 3..5 {
+            let head = commit(&upstream, &[("file", &format!("c{}", i))]);
+            repo.update(path, &ctx).unwrap();
+            let work = Repository::open(path).unwrap();
+            assert_eq!(work.head().unwrap().target(), Some(head));
+            // 原来的边界保留, 整条历史都能遍历到
+            assert!(work.is_shallow());
+            let mut walk = work.revwalk().unwrap();
+            walk.push_head().unwrap();
+            assert_eq!(walk.collect::<Result<Vec<_>, _>>().unwrap().len(), i - 1);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::cancel;
+use crate::error::SyncError;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// 克隆和拉取的重试策略, 只重试 [`SyncError::is_transient`] 判定为临时的错误
+///
+/// ```toml
+/// [repos.options.retry]
+/// attempts = 5
+/// delay_ms = 2000
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// 最多尝试的次数, 包括第一次, 为 1 时不重试
+    pub attempts: u32,
+    /// 第一次重试前等待的毫秒数, 之后每次翻倍
+    pub delay_ms: u64,
+    /// 等待时间的上限 (毫秒)
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay_ms: 1000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u64 << retry.min(16);
+        Duration::from_millis(self.delay_ms.saturating_mul(factor).min(self.max_delay_ms))
+    }
+
+    /// 执行 `f`, 临时错误按策略等待后重试, 永久错误或次数用完时返回最后一次的错误.
+    /// `f` 失败时要自己清理留下的半成品, 保证下一次尝试从干净的状态开始
+    pub fn run<T>(
+        &self,
+        repo: &str,
+        what: &str,
+        mut f: impl FnMut() -> Result<T, SyncError>,
+    ) -> Result<T, SyncError> {
+        let attempts = self.attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(e) if attempt < attempts && e.is_transient() && !cancel::requested() => {
+                    let delay = self.delay(attempt - 1);
+                    println!(
+                        "[{}] {} 失败 ({}/{}): {}, {:?} 后重试",
+                        repo, what, attempt, attempts, e, delay
+                    );
+                    if !cancel::sleep(delay) {
+                        return Err(SyncError::Cancelled);
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
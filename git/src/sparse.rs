@@ -0,0 +1,143 @@
+use git2::build::CheckoutBuilder;
+use git2::{Index, IndexEntryExtendedFlag, IndexEntryFlag, Pathspec, PathspecFlags, Repository};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// 稀疏检出: 只检出匹配 `include` 且不匹配 `exclude` 的文件, 模式与 git pathspec 相同,
+/// 目录名匹配其下所有文件, 支持 `*` 通配. `include` 为空时包含全部文件.
+///
+/// libgit2 不支持 git 的 sparse-checkout, 这里用 CheckoutBuilder 的路径过滤代替,
+/// 检出后把未包含的索引项标记为 skip-worktree, 使其不被当作本地删除.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sparse {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Sparse {
+    pub fn is_enabled(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    /// pathspec 按顺序取第一个匹配的模式, 所以排除的模式放在前面
+    fn patterns(&self) -> Vec<String> {
+        let mut patterns: Vec<String> = self.exclude.iter().map(|p| format!("!{}", p)).collect();
+        if self.include.is_empty() {
+            patterns.push("*".to_string());
+        } else {
+            patterns.extend(self.include.iter().cloned());
+        }
+        patterns
+    }
+
+    /// 限制检出范围, 未启用时不做任何改动
+    pub fn filter(&self, cb: &mut CheckoutBuilder) {
+        if self.is_enabled() {
+            for p in self.patterns() {
+                cb.path(p);
+            }
+        }
+    }
+
+    /// 让工作区和索引符合当前配置, 在每次克隆和拉取之后调用.
+    ///
+    /// 补齐新包含进来的文件, 删除不再包含的文件, 再按 HEAD 重建索引并标记
+    /// skip-worktree. 同时写入 `core.sparseCheckout` 和 `info/sparse-checkout`,
+    /// 命令行 git 也会遵循同样的范围. 返回未检出的文件数.
+    pub fn apply(
+        &self,
+        repo: &Repository,
+        mut checkout: CheckoutBuilder,
+    ) -> Result<usize, git2::Error> {
+        let mut config = repo.config()?;
+        let was_enabled = config.get_bool("core.sparseCheckout").unwrap_or(false);
+        if !self.is_enabled() && !was_enabled {
+            return Ok(0);
+        }
+
+        self.filter(&mut checkout);
+        repo.checkout_head(Some(checkout.safe().recreate_missing(true)))?;
+
+        let tree = repo.head()?.peel_to_tree()?;
+        let mut index = repo.index()?;
+        // read_tree 会保留内容未变的索引项的 stat 信息, 但清除扩展标记
+        index.read_tree(&tree)?;
+        let spec = Pathspec::new(self.patterns())?;
+        let workdir = repo.workdir().map(Path::to_path_buf);
+        let excluded: Vec<_> = index
+            .iter()
+            .filter(|e| {
+                self.is_enabled()
+                    && !spec.matches_path(
+                        Path::new(&*String::from_utf8_lossy(&e.path)),
+                        PathspecFlags::DEFAULT,
+                    )
+            })
+            .collect();
+        let mut skipped = vec![];
+        for mut entry in excluded {
+            skipped.push(String::from_utf8_lossy(&entry.path).to_string());
+            entry.flags |= IndexEntryFlag::EXTENDED.bits();
+            entry.flags_extended |= IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+            index.add(&entry)?;
+        }
+        index.write()?;
+
+        if let Some(workdir) = workdir {
+            for path in &skipped {
+                remove_file(&workdir, Path::new(path))
+                    .map_err(|e| git2::Error::from_str(&format!("删除 {} 失败: {}", path, e)))?;
+            }
+        }
+
+        config.set_bool("core.sparseCheckout", self.is_enabled())?;
+        self.write_info(repo).map_err(|e| {
+            git2::Error::from_str(&format!("写入 info/sparse-checkout 失败: {}", e))
+        })?;
+        Ok(skipped.len())
+    }
+
+    /// 以 gitignore 语法写出相同的范围, 供命令行 git 使用
+    fn write_info(&self, repo: &Repository) -> io::Result<()> {
+        let file = repo.path().join("info").join("sparse-checkout");
+        if !self.is_enabled() {
+            return match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut lines = vec![];
+        if self.include.is_empty() {
+            lines.push("/*".to_string());
+        } else {
+            lines.extend(self.include.iter().cloned());
+        }
+        lines.extend(self.exclude.iter().map(|p| format!("!{}", p)));
+        fs::create_dir_all(file.parent().unwrap_or(repo.path()))?;
+        fs::write(file, lines.join("\n") + "\n")
+    }
+}
+
+/// 删除工作区中的文件, 并清理因此变空的上级目录
+fn remove_file(workdir: &Path, path: &Path) -> io::Result<()> {
+    match fs::remove_file(workdir.join(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        r => r?,
+    }
+    for dir in path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(workdir.join(dir)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// 该路径是否被稀疏检出跳过 (索引项带 skip-worktree 标记)
+pub fn is_skipped(index: &Index, path: &Path) -> bool {
+    index
+        .get_path(path, 0)
+        .is_some_and(|e| e.flags_extended & IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0)
+}
@@ -0,0 +1,257 @@
+use crate::repo::{self, Repo};
+use crate::sparse;
+use git2::{BranchType, Repository, Status, StatusOptions};
+use serde::Serialize;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// HEAD 指向的提交, `time` 为 Unix 秒
+#[derive(Debug, Serialize)]
+pub struct HeadCommit {
+    pub id: String,
+    pub author: String,
+    pub time: i64,
+    pub summary: String,
+}
+
+/// 各类修改的文件数, 稀疏检出跳过的文件和子模块不计入
+#[derive(Debug, Default, Serialize)]
+pub struct DirtyCounts {
+    pub staged: usize,
+    pub modified: usize,
+    pub untracked: usize,
+    pub conflicted: usize,
+}
+
+impl DirtyCounts {
+    pub fn is_clean(&self) -> bool {
+        self.staged + self.modified + self.untracked + self.conflicted == 0
+    }
+}
+
+/// 清单中一个仓库的状态
+#[derive(Debug, Default, Serialize)]
+pub struct RepoStatus {
+    pub path: String,
+    pub cloned: bool,
+    /// 当前分支, 分离 HEAD 时为 `None`
+    pub branch: Option<String>,
+    pub head: Option<HeadCommit>,
+    /// 用来计算 ahead/behind 的远端跟踪分支
+    pub upstream: Option<String>,
+    pub ahead: Option<usize>,
+    pub behind: Option<usize>,
+    /// 裸仓库 (镜像) 没有工作区, 为 `None`
+    pub dirty: Option<DirtyCounts>,
+    /// 上次成功同步的时间, Unix 秒
+    pub last_sync: Option<i64>,
+    /// 读取状态失败的原因
+    pub error: Option<String>,
+}
+
+impl RepoStatus {
+    pub fn collect(repo: &Repo) -> RepoStatus {
+        let mut status = RepoStatus {
+            path: repo.path.clone(),
+            ..Default::default()
+        };
+        let r = match Repository::open(&repo.path) {
+            Ok(r) => r,
+            Err(_) => return status,
+        };
+        status.cloned = true;
+        if let Err(e) = status.read(repo, &r) {
+            status.error = Some(e.message().to_string());
+        }
+        status
+    }
+
+    fn read(&mut self, repo: &Repo, r: &Repository) -> Result<(), git2::Error> {
+        self.last_sync = repo::last_sync(r);
+        let head = r.head()?;
+        if head.is_branch() {
+            self.branch = head.shorthand().map(str::to_string);
+        }
+        let commit = repo.find_last_commit(r)?;
+        self.head = Some(HeadCommit {
+            id: commit.id().to_string(),
+            author: commit.author().name().unwrap_or_default().to_string(),
+            time: commit.time().seconds(),
+            summary: commit.summary().unwrap_or_default().to_string(),
+        });
+
+        if let Some((name, upstream)) = self.upstream_of(repo, r) {
+            let (ahead, behind) = r.graph_ahead_behind(commit.id(), upstream)?;
+            self.upstream = Some(name);
+            self.ahead = Some(ahead);
+            self.behind = Some(behind);
+        }
+        if !r.is_bare() {
+            self.dirty = Some(dirty_counts(r)?);
+        }
+        Ok(())
+    }
+
+    /// 优先使用分支配置的上游, 没有时按清单中的 remote 和 branch 查找
+    fn upstream_of(&self, repo: &Repo, r: &Repository) -> Option<(String, git2::Oid)> {
+        let branch = self.branch.as_deref()?;
+        if let Ok(upstream) = r
+            .find_branch(branch, BranchType::Local)
+            .and_then(|b| b.upstream())
+        {
+            let name = upstream.name().ok().flatten()?.to_string();
+            return Some((name, upstream.get().target()?));
+        }
+        let name = format!("{}/{}", repo.options.remote, branch);
+        let target = r
+            .find_reference(&format!("refs/remotes/{}", name))
+            .ok()?
+            .target()?;
+        Some((name, target))
+    }
+}
+
+fn dirty_counts(repo: &Repository) -> Result<DirtyCounts, git2::Error> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let index = repo.index()?;
+    let mut counts = DirtyCounts::default();
+    for entry in repo.statuses(Some(&mut opts))?.iter() {
+        let s = entry.status();
+        if entry
+            .path()
+            .is_some_and(|p| sparse::is_skipped(&index, Path::new(p)))
+        {
+            continue;
+        }
+        if s.is_conflicted() {
+            counts.conflicted += 1;
+            continue;
+        }
+        if s.is_wt_new() {
+            counts.untracked += 1;
+        }
+        if s.intersects(
+            Status::INDEX_NEW
+                | Status::INDEX_MODIFIED
+                | Status::INDEX_DELETED
+                | Status::INDEX_RENAMED
+                | Status::INDEX_TYPECHANGE,
+        ) {
+            counts.staged += 1;
+        }
+        if s.intersects(
+            Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_RENAMED | Status::WT_TYPECHANGE,
+        ) {
+            counts.modified += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// 距今多久, 用于表格输出
+fn ago(secs: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    match now - secs {
+        d if d < 60 => "刚刚".to_string(),
+        d if d < 3600 => format!("{} 分钟前", d / 60),
+        d if d < 86400 => format!("{} 小时前", d / 3600),
+        d => format!("{} 天前", d / 86400),
+    }
+}
+
+/// 终端中的显示宽度, 中日韩字符按两列计算
+fn width(s: &str) -> usize {
+    s.chars()
+        .map(|c| if (c as u32) >= 0x1100 { 2 } else { 1 })
+        .sum()
+}
+
+/// 以对齐的表格打印, 每个仓库一行
+pub fn print_table(statuses: &[RepoStatus]) {
+    let header = [
+        "仓库",
+        "分支",
+        "HEAD",
+        "领先/落后",
+        "修改",
+        "上次同步",
+        "最近提交",
+    ];
+    let rows: Vec<[String; 7]> = statuses.iter().map(row).collect();
+    print_aligned(&header, &rows);
+}
+
+/// 按列对齐打印表头和各行
+pub fn print_aligned<const N: usize>(header: &[&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(width);
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(width(cell));
+        }
+    }
+    let print = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{}{}", cell, " ".repeat(w - width(cell))))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print(header);
+    for row in rows {
+        print(&row.each_ref().map(String::as_str));
+    }
+}
+
+fn row(s: &RepoStatus) -> [String; 7] {
+    let dash = || "-".to_string();
+    if !s.cloned {
+        return [
+            s.path.clone(),
+            dash(),
+            dash(),
+            dash(),
+            dash(),
+            dash(),
+            "未克隆".to_string(),
+        ];
+    }
+    let branch = match (&s.branch, &s.head) {
+        (Some(b), _) => b.clone(),
+        (None, Some(_)) => "(分离)".to_string(),
+        (None, None) => dash(),
+    };
+    let head = s.head.as_ref().map_or_else(dash, |h| h.id[..8].to_string());
+    let ahead_behind = match (s.ahead, s.behind) {
+        (Some(a), Some(b)) => format!("+{} -{}", a, b),
+        _ => dash(),
+    };
+    let dirty = match &s.dirty {
+        Some(d) if d.is_clean() => "干净".to_string(),
+        Some(d) => format!(
+            "暂存 {} 修改 {} 未跟踪 {} 冲突 {}",
+            d.staged, d.modified, d.untracked, d.conflicted
+        ),
+        None => dash(),
+    };
+    let last_sync = s.last_sync.map_or_else(dash, ago);
+    let commit = match (&s.error, &s.head) {
+        (Some(e), _) => format!("读取失败: {}", e),
+        (None, Some(h)) => format!("{} ({}, {})", h.summary, h.author, ago(h.time)),
+        (None, None) => dash(),
+    };
+    [
+        s.path.clone(),
+        branch,
+        head,
+        ahead_behind,
+        dirty,
+        last_sync,
+        commit,
+    ]
+}